@@ -0,0 +1,311 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::FileLink;
+
+/// Links of the inodes that were already loaded from the storage.
+///
+/// Besides the links, the cache keeps the listings of the directories that were read completely,
+///   a listing is dropped as soon as one of its children is evicted. When the cache is full,
+///   the least recently used inodes are evicted, except the ones the kernel still knows about
+///   (their lookup count isn't zero).
+///
+/// An inode may be listed in several directories under different names, the returned links
+///   are named by the listing.
+///
+/// Links and listings restored from the previous mount may be outdated: the links are checked
+///   by the caller (`is_restored`), the listings are not returned until they are set again.
+pub struct InodeCache {
+    entries: HashMap<u64, CachedInode>,
+    /// Children of the directories by name.
+    listings: HashMap<u64, BTreeMap<String, u64>>,
+    /// Directories which listings were restored from the previous mount and weren't read since.
+    restored_listings: HashSet<u64>,
+    /// Inodes that may be evicted, by the time of the last use.
+    usage: BTreeMap<u64, u64>,
+    clock: u64,
+    capacity: usize,
+}
+
+struct CachedInode {
+    link: FileLink,
+    /// Directories the inode was seen in.
+    parents: Vec<u64>,
+    lookups: u64,
+    last_used: u64,
+    /// The link was restored from the previous mount and may be outdated.
+    restored: bool,
+}
+
+/// Content of the cache saved between mounts.
+#[derive(Serialize, Deserialize)]
+pub struct CacheSnapshot {
+    /// Id of the filesystem the cache belongs to, see `StorageBackend::storage_id`.
+    storage: u64,
+    /// From the least recently used.
+    inodes: Vec<SavedInode>,
+    listings: HashMap<u64, BTreeMap<String, u64>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedInode {
+    ino: u64,
+    parents: Vec<u64>,
+    link: FileLink,
+}
+
+impl CacheSnapshot {
+    /// `true` if the cache was saved by a mount of the filesystem with the given id.
+    pub fn belongs_to(&self, storage: u64) -> bool {
+        self.storage == storage
+    }
+}
+
+impl InodeCache {
+    pub fn new(capacity: usize) -> InodeCache {
+        InodeCache {
+            entries: HashMap::new(),
+            listings: HashMap::new(),
+            restored_listings: HashSet::new(),
+            usage: BTreeMap::new(),
+            clock: 0,
+            capacity,
+        }
+    }
+
+    pub fn get(&mut self, ino: u64) -> Option<FileLink> {
+        self.touch(ino);
+        self.entries.get(&ino).map(|x| x.link.clone())
+    }
+
+    /// Add the link or replace the cached one. `parent` is `None` if it's unknown.
+    pub fn insert(&mut self, ino: u64, parent: Option<u64>, link: FileLink) {
+        self.put(ino, parent, link);
+        self.evict();
+    }
+
+    /// `true` if the link was restored from the previous mount and wasn't replaced since then.
+    pub fn is_restored(&self, ino: u64) -> bool {
+        self.entries.get(&ino).map(|x| x.restored).unwrap_or(false)
+    }
+
+    pub fn update<F: FnOnce(&mut FileLink)>(&mut self, ino: u64, f: F) {
+        if let Some(entry) = self.entries.get_mut(&ino) {
+            f(&mut entry.link);
+        }
+    }
+
+    /// Remember all the children of the directory.
+    pub fn set_children(&mut self, directory: u64, files: Vec<FileLink>) {
+        let mut listing = BTreeMap::new();
+        for file in files {
+            let ino = file.attr.ino;
+            listing.insert(file.name.clone(), ino);
+            self.put(ino, Some(directory), file);
+        }
+        self.listings.insert(directory, listing);
+        self.restored_listings.remove(&directory);
+        self.evict();
+    }
+
+    /// Children of the directory, `None` if the listing isn't cached or is restored.
+    pub fn children(&mut self, directory: u64) -> Option<Vec<FileLink>> {
+        if self.restored_listings.contains(&directory) {
+            return None;
+        }
+        let listing: Vec<(String, u64)> = self
+            .listings
+            .get(&directory)?
+            .iter()
+            .map(|(name, &ino)| (name.clone(), ino))
+            .collect();
+        Some(
+            listing
+                .into_iter()
+                .filter_map(|(name, ino)| self.get_named(ino, name))
+                .collect(),
+        )
+    }
+
+    /// `None` if the listing of the directory isn't cached or is restored,
+    ///   `Some(None)` if there is no such child.
+    pub fn find_child(&mut self, directory: u64, name: &str) -> Option<Option<FileLink>> {
+        if self.restored_listings.contains(&directory) {
+            return None;
+        }
+        let ino = self.listings.get(&directory)?.get(name).cloned();
+        Some(ino.and_then(|x| self.get_named(x, name.to_string())))
+    }
+
+    /// Add the new child to the directory.
+    pub fn add_child(&mut self, directory: u64, link: FileLink) {
+        let ino = link.attr.ino;
+        if let Some(listing) = self.listings.get_mut(&directory) {
+            listing.insert(link.name.clone(), ino);
+        }
+        self.insert(ino, Some(directory), link);
+    }
+
+    /// Move the name of the inode to another directory under the new name.
+    pub fn rename(&mut self, ino: u64, name: &str, parent: u64, new_name: &str, new_parent: u64) {
+        if let Some(listing) = self.listings.get_mut(&parent) {
+            listing.remove(name);
+        }
+
+        let entry = match self.entries.get_mut(&ino) {
+            Some(data) => data,
+            None => {
+                // The listing can't be kept without its child
+                self.listings.remove(&new_parent);
+                return;
+            }
+        };
+
+        entry.link.name = new_name.to_string();
+        if !entry.parents.contains(&new_parent) {
+            entry.parents.push(new_parent);
+        }
+        if let Some(listing) = self.listings.get_mut(&new_parent) {
+            listing.insert(new_name.to_string(), ino);
+        }
+    }
+
+    /// Forget one name of the inode, the inode itself is kept.
+    pub fn remove_child(&mut self, directory: u64, name: &str) {
+        if let Some(listing) = self.listings.get_mut(&directory) {
+            listing.remove(name);
+        }
+    }
+
+    /// Forget the removed inode with all its names.
+    pub fn remove(&mut self, ino: u64) {
+        if let Some(entry) = self.entries.remove(&ino) {
+            self.usage.remove(&entry.last_used);
+            for parent in entry.parents {
+                if let Some(listing) = self.listings.get_mut(&parent) {
+                    listing.retain(|_, x| *x != ino);
+                }
+            }
+        }
+        self.listings.remove(&ino);
+    }
+
+    /// The kernel got a reference to the inode, it's not evicted until `forget`.
+    pub fn lookup(&mut self, ino: u64) {
+        if let Some(entry) = self.entries.get_mut(&ino) {
+            if entry.lookups == 0 {
+                self.usage.remove(&entry.last_used);
+            }
+            entry.lookups += 1;
+        }
+    }
+
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        if let Some(entry) = self.entries.get_mut(&ino) {
+            entry.lookups = entry.lookups.saturating_sub(nlookup);
+            if entry.lookups == 0 {
+                self.usage.insert(entry.last_used, ino);
+            }
+        }
+        self.evict();
+    }
+
+    /// `storage` is the id of the filesystem the cache belongs to.
+    pub fn snapshot(&self, storage: u64) -> CacheSnapshot {
+        let mut entries: Vec<(&u64, &CachedInode)> = self.entries.iter().collect();
+        entries.sort_by_key(|(_, entry)| entry.last_used);
+
+        let inodes = entries
+            .into_iter()
+            .map(|(&ino, entry)| SavedInode {
+                ino,
+                parents: entry.parents.clone(),
+                link: entry.link.clone(),
+            })
+            .collect();
+        CacheSnapshot {
+            storage,
+            inodes,
+            listings: self.listings.clone(),
+        }
+    }
+
+    /// Fill the cache with the links saved by the previous mount.
+    pub fn restore(&mut self, snapshot: CacheSnapshot) {
+        for saved in snapshot.inodes {
+            self.put(saved.ino, None, saved.link);
+            let entry = self.entries.get_mut(&saved.ino).unwrap();
+            entry.parents = saved.parents;
+            entry.restored = true;
+        }
+        for (directory, listing) in snapshot.listings {
+            if listing.values().all(|x| self.entries.contains_key(x)) {
+                self.listings.insert(directory, listing);
+                self.restored_listings.insert(directory);
+            }
+        }
+        self.evict();
+    }
+
+    fn put(&mut self, ino: u64, parent: Option<u64>, link: FileLink) {
+        match self.entries.get_mut(&ino) {
+            Some(entry) => {
+                entry.link = link;
+                if let Some(data) = parent {
+                    if !entry.parents.contains(&data) {
+                        entry.parents.push(data);
+                    }
+                }
+                entry.restored = false;
+                self.touch(ino);
+            }
+            None => {
+                self.clock += 1;
+                let entry = CachedInode {
+                    link,
+                    parents: parent.into_iter().collect(),
+                    lookups: 0,
+                    last_used: self.clock,
+                    restored: false,
+                };
+                self.entries.insert(ino, entry);
+                self.usage.insert(self.clock, ino);
+            }
+        }
+    }
+
+    fn get_named(&mut self, ino: u64, name: String) -> Option<FileLink> {
+        let mut link = self.get(ino)?;
+        link.name = name;
+        Some(link)
+    }
+
+    fn touch(&mut self, ino: u64) {
+        if let Some(entry) = self.entries.get_mut(&ino) {
+            self.clock += 1;
+            if entry.lookups == 0 {
+                self.usage.remove(&entry.last_used);
+                self.usage.insert(self.clock, ino);
+            }
+            entry.last_used = self.clock;
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let (last_used, ino) = match self.usage.iter().next() {
+                Some((&last_used, &ino)) => (last_used, ino),
+                None => break,
+            };
+            self.usage.remove(&last_used);
+
+            if let Some(entry) = self.entries.remove(&ino) {
+                for parent in entry.parents {
+                    self.listings.remove(&parent);
+                }
+            }
+            self.listings.remove(&ino);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::error::{FsError, FsResult};
+use crate::types::FpfsInputFile;
+
+/// A message stored in the chat.
+#[derive(Clone)]
+pub struct ChatMessage {
+    pub id: i32,
+    pub text: String,
+}
+
+pub enum EditError {
+    /// Telegram doesn't allow to edit old messages (`MESSAGE_EDIT_TIME_EXPIRED`),
+    ///   such messages should be sent again.
+    TimeExpired,
+    Failed(FsError),
+}
+
+impl From<FsError> for EditError {
+    fn from(e: FsError) -> Self {
+        EditError::Failed(e)
+    }
+}
+
+/// The message model `TgConnection` is built on: numbered messages with text and an optional
+///   attached file.
+///
+/// The real implementation talks to telegram (`TgChat`), `MockChat` emulates it locally.
+#[async_trait]
+pub trait Chat: Send + Sync {
+    /// Send a new message and return its id.
+    async fn send_message(&self, text: String, file: Option<FpfsInputFile>) -> FsResult<i32>;
+
+    async fn edit_message(
+        &self,
+        id: i32,
+        text: String,
+        file: Option<FpfsInputFile>,
+    ) -> Result<(), EditError>;
+
+    async fn delete_messages(&self, ids: &[i32]) -> FsResult<()>;
+
+    /// Messages in the same order as `ids`, `None` for the missing ones.
+    async fn get_messages(&self, ids: &[i32]) -> FsResult<Vec<Option<ChatMessage>>>;
+
+    /// The newest message which text satisfies the filter.
+    async fn find_message(
+        &self,
+        filter: &(dyn Fn(&str) -> bool + Sync),
+    ) -> FsResult<Option<ChatMessage>>;
+
+    /// Pin the message, so `find_pinned_message` finds it without going through the history.
+    async fn pin_message(&self, id: i32) -> FsResult<()>;
+
+    /// The newest pinned message which text satisfies the filter.
+    async fn find_pinned_message(
+        &self,
+        filter: &(dyn Fn(&str) -> bool + Sync),
+    ) -> FsResult<Option<ChatMessage>>;
+
+    /// Upload the file so it can be attached to a message.
+    async fn upload_file(&self, path: &Path) -> FsResult<FpfsInputFile>;
+
+    /// Content of the file attached to the message, `FsError::NotFound` if there is no such file.
+    async fn download_media(&self, id: i32) -> FsResult<Vec<u8>>;
+
+    /// `size` bytes of the attached file starting from `offset`, less if the file ends earlier.
+    async fn download_range(&self, id: i32, offset: u64, size: u64) -> FsResult<Vec<u8>>;
+
+    /// Length of the text in characters as it's stored in the chat, telegram limits it.
+    fn text_length(&self, text: &str) -> usize {
+        text.chars().count()
+    }
+}
+
+/// The same chat shared by several connections, e.g. several mounts of one `MockChat`.
+#[async_trait]
+impl<C: Chat> Chat for Arc<C> {
+    async fn send_message(&self, text: String, file: Option<FpfsInputFile>) -> FsResult<i32> {
+        (**self).send_message(text, file).await
+    }
+
+    async fn edit_message(
+        &self,
+        id: i32,
+        text: String,
+        file: Option<FpfsInputFile>,
+    ) -> Result<(), EditError> {
+        (**self).edit_message(id, text, file).await
+    }
+
+    async fn delete_messages(&self, ids: &[i32]) -> FsResult<()> {
+        (**self).delete_messages(ids).await
+    }
+
+    async fn get_messages(&self, ids: &[i32]) -> FsResult<Vec<Option<ChatMessage>>> {
+        (**self).get_messages(ids).await
+    }
+
+    async fn find_message(
+        &self,
+        filter: &(dyn Fn(&str) -> bool + Sync),
+    ) -> FsResult<Option<ChatMessage>> {
+        (**self).find_message(filter).await
+    }
+
+    async fn pin_message(&self, id: i32) -> FsResult<()> {
+        (**self).pin_message(id).await
+    }
+
+    async fn find_pinned_message(
+        &self,
+        filter: &(dyn Fn(&str) -> bool + Sync),
+    ) -> FsResult<Option<ChatMessage>> {
+        (**self).find_pinned_message(filter).await
+    }
+
+    async fn upload_file(&self, path: &Path) -> FsResult<FpfsInputFile> {
+        (**self).upload_file(path).await
+    }
+
+    async fn download_media(&self, id: i32) -> FsResult<Vec<u8>> {
+        (**self).download_media(id).await
+    }
+
+    async fn download_range(&self, id: i32, offset: u64, size: u64) -> FsResult<Vec<u8>> {
+        (**self).download_range(id, offset, size).await
+    }
+
+    fn text_length(&self, text: &str) -> usize {
+        (**self).text_length(text)
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use log::LevelFilter;
+
+/// The name the binary is called by `mount -t fpfs` and `/etc/fstab`.
+const MOUNT_HELPER: &'static str = "mount.fpfs";
+
+/// The source of the `/etc/fstab` entry that means the default config.
+const DEFAULT_SOURCE: &'static str = "fpfs";
+
+pub const USAGE: &'static str = "Usage: fpfs <command> [options]
+
+Commands:
+  mount <dir>      Mount the filesystem
+  unmount <dir>    Unmount the filesystem
+  login            Sign in and save the session, `--bot <token>` signs in a bot
+  status           Show the session, the storage chat and the stored filesystem
+  cleanup          Remove the filesystem from the storage chat, asks for confirmation unless `--yes`
+  clear-cache      Remove the local cache
+  help             Show this message
+
+Mount options:
+  --read-only            Mount read-only
+  --allow-other          Allow access to other users
+  --uid <uid>            Show all the files as owned by this user
+  --gid <gid>            Show all the files as owned by this group
+  --compress             Compress the content of the files with zstd
+  -f, --foreground       Don't detach from the terminal
+  -o <options>           Comma separated options: ro, allow_other, uid=<uid>, gid=<gid>,
+                         foreground, compress and any of the flags below, e.g. config=<path>
+
+Common options:
+  --config <path>        Config file, `fpfs.json` by default
+  --api-id, --api-hash, --chat, --session, --cache-dir, --cache-size, --requests-per-minute,
+  --encryption-key, --encryption-key-file
+                         Override the value from the config
+  --log-level <level>    off, error, warn, info, debug or trace; info by default
+
+As a mount helper: mount.fpfs <config | fpfs> <dir> [-o <options>]";
+
+/// Flags that don't take a value.
+const SWITCHES: [&'static str; 5] = ["read-only", "allow-other", "foreground", "compress", "yes"];
+
+pub enum Command {
+    Mount {
+        mountpoint: String,
+        options: MountOptions,
+    },
+    Unmount {
+        mountpoint: String,
+    },
+    Login {
+        bot_token: Option<String>,
+    },
+    Status,
+    Cleanup {
+        confirmed: bool,
+    },
+    ClearCache,
+    Help,
+}
+
+pub struct MountOptions {
+    pub read_only: bool,
+    pub allow_other: bool,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub foreground: bool,
+    /// Compress the content of the files, see `COMPRESSION_XATTR`.
+    pub compress: bool,
+}
+
+/// Parsed command line.
+pub struct Cli {
+    pub command: Command,
+    /// Flags with values, without leading dashes. They are passed to `Config::load`.
+    pub flags: HashMap<String, String>,
+    pub log_level: LevelFilter,
+}
+
+impl Cli {
+    /// `args` include the program name, it decides whether the binary works as a mount helper.
+    pub fn parse(args: &[String]) -> Result<Cli, String> {
+        let program = args
+            .first()
+            .and_then(|x| Path::new(x).file_name())
+            .and_then(|x| x.to_str())
+            .unwrap_or_default();
+        let (mut flags, switches, arguments) = parse_args(args.get(1..).unwrap_or_default())?;
+
+        let log_level = match flags.remove("log-level") {
+            Some(data) => data
+                .parse()
+                .map_err(|_| format!("Invalid log level: {}", data))?,
+            None => LevelFilter::Info,
+        };
+
+        let command = if program == MOUNT_HELPER {
+            // mount(8) calls `mount.fpfs <source> <dir> -o <options>`
+            let (source, mountpoint) = match arguments.as_slice() {
+                [source, mountpoint] => (source, mountpoint),
+                _ => return Err(USAGE.to_string()),
+            };
+            if source != DEFAULT_SOURCE {
+                flags.entry("config".to_string()).or_insert(source.clone());
+            }
+            Command::Mount {
+                mountpoint: mountpoint.clone(),
+                options: MountOptions::new(&flags, &switches)?,
+            }
+        } else {
+            let argument = |index: usize, name: &str| -> Result<String, String> {
+                match arguments.get(index) {
+                    Some(data) => Ok(data.clone()),
+                    None => Err(format!("{} is missing\n\n{}", name, USAGE)),
+                }
+            };
+
+            match arguments.first().map(|x| x.as_str()) {
+                Some("mount") => Command::Mount {
+                    mountpoint: argument(1, "Mount point")?,
+                    options: MountOptions::new(&flags, &switches)?,
+                },
+                Some("unmount") | Some("umount") => Command::Unmount {
+                    mountpoint: argument(1, "Mount point")?,
+                },
+                Some("login") => Command::Login {
+                    bot_token: flags.get("bot").cloned(),
+                },
+                Some("status") => Command::Status,
+                Some("cleanup") => Command::Cleanup {
+                    confirmed: switches.contains("yes"),
+                },
+                Some("clear-cache") => Command::ClearCache,
+                Some("help") | None => Command::Help,
+                Some(command) => return Err(format!("Unknown command: {}\n\n{}", command, USAGE)),
+            }
+        };
+
+        Ok(Cli {
+            command,
+            flags,
+            log_level,
+        })
+    }
+}
+
+impl MountOptions {
+    fn new(
+        flags: &HashMap<String, String>,
+        switches: &HashSet<String>,
+    ) -> Result<MountOptions, String> {
+        let id = |name: &str| -> Result<Option<u32>, String> {
+            match flags.get(name) {
+                Some(data) => match data.parse() {
+                    Ok(parsed) => Ok(Some(parsed)),
+                    Err(_) => Err(format!("Invalid {}: {}", name, data)),
+                },
+                None => Ok(None),
+            }
+        };
+
+        Ok(MountOptions {
+            read_only: switches.contains("read-only"),
+            allow_other: switches.contains("allow-other"),
+            uid: id("uid")?,
+            gid: id("gid")?,
+            foreground: switches.contains("foreground"),
+            compress: switches.contains("compress"),
+        })
+    }
+}
+
+/// Split the arguments into `--name value` flags, switches and the rest.
+///   Options of `-o` are added to the flags and switches.
+fn parse_args(
+    args: &[String],
+) -> Result<(HashMap<String, String>, HashSet<String>, Vec<String>), String> {
+    let mut flags = HashMap::new();
+    let mut switches = HashSet::new();
+    let mut arguments = vec![];
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "-f" {
+            switches.insert("foreground".to_string());
+        } else if arg == "-o" {
+            let options = iter.next().ok_or("-o needs a value")?;
+            for option in options.split(',') {
+                add_mount_option(option, &mut flags, &mut switches);
+            }
+        } else if arg.starts_with("--") {
+            let name = arg[2..].to_string();
+            if SWITCHES.contains(&name.as_str()) {
+                switches.insert(name);
+            } else {
+                let value = iter.next().ok_or(format!("{} needs a value", arg))?;
+                flags.insert(name, value.clone());
+            }
+        } else if arg.starts_with('-') {
+            // Options of mount(8) like `-n` or `-v` mean nothing for us
+            continue;
+        } else {
+            arguments.push(arg.clone());
+        }
+    }
+    Ok((flags, switches, arguments))
+}
+
+/// Options of `-o` use the names of the usual mount options, e.g. `ro` or `allow_other`.
+///   Unknown options like `noauto` or `_netdev` are meant for mount(8) and are skipped.
+fn add_mount_option(
+    option: &str,
+    flags: &mut HashMap<String, String>,
+    switches: &mut HashSet<String>,
+) {
+    let (name, value) = match option.find('=') {
+        Some(index) => (&option[..index], Some(&option[index + 1..])),
+        None => (option, None),
+    };
+    let name = name.replace('_', "-");
+
+    match (name.as_str(), value) {
+        ("ro", None) => {
+            switches.insert("read-only".to_string());
+        }
+        ("rw", None) => {
+            switches.remove("read-only");
+        }
+        (switch, None) if SWITCHES.contains(&switch) => {
+            switches.insert(switch.to_string());
+        }
+        (flag, Some(data)) => {
+            flags.insert(flag.to_string(), data.to_string());
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use tempfile::NamedTempFile;
+
+use crate::types::FileLink;
+
+/// Xattr that turns the compression on (`on`) or off (`off`) for the file, it overrides
+///   the mount option. New files and directories take it from their directory.
+pub const COMPRESSION_XATTR: &'static str = "user.fpfs.compress";
+
+/// zstd level, the default one is fast enough not to slow down the upload.
+const LEVEL: i32 = 3;
+
+/// The beginning of the file is compressed first, to skip the data that is compressed already,
+///   like media or archives.
+const SAMPLE_SIZE: u64 = 128 * 1024;
+
+/// The data is stored compressed only if it becomes at least this much smaller, in percent.
+const MIN_SAVING: u64 = 10;
+
+/// The content is compressed by frames of this size, every frame can be decompressed on its own,
+///   so a read downloads only the frames it needs.
+pub const FRAME_SIZE: u64 = 1024 * 1024;
+
+/// Compressed copy of the file and the compressed sizes of its frames.
+pub struct Compressed {
+    pub file: NamedTempFile,
+    pub frames: Vec<u32>,
+}
+
+/// Whether the content of the file should be compressed, `default` is the mount option.
+pub fn is_enabled(link: &FileLink, default: bool) -> bool {
+    match link.xattr.get(COMPRESSION_XATTR).map(|x| x.as_slice()) {
+        Some(b"on") => true,
+        Some(b"off") => false,
+        _ => default,
+    }
+}
+
+/// The new file or directory takes the compression xattr from its directory.
+pub fn inherit(parent: &FileLink, child: &mut FileLink) {
+    if let Some(value) = parent.xattr.get(COMPRESSION_XATTR) {
+        child
+            .xattr
+            .insert(COMPRESSION_XATTR.to_string(), value.clone());
+    }
+}
+
+/// Compressed copy of the file, `None` if it doesn't compress well.
+pub fn compress(path: &Path) -> io::Result<Option<Compressed>> {
+    let size = path.metadata()?.len();
+
+    let mut sample = vec![];
+    File::open(path)?
+        .take(SAMPLE_SIZE)
+        .read_to_end(&mut sample)?;
+    let compressed_sample = zstd::encode_all(sample.as_slice(), LEVEL)?;
+    if !is_worth(sample.len() as u64, compressed_sample.len() as u64) {
+        return Ok(None);
+    }
+
+    let mut file = File::open(path)?;
+    let mut compressed = NamedTempFile::new()?;
+    let mut frames = vec![];
+    let mut compressed_size = 0;
+    loop {
+        let mut frame = vec![];
+        file.by_ref().take(FRAME_SIZE).read_to_end(&mut frame)?;
+        if frame.is_empty() {
+            break;
+        }
+        let data = zstd::encode_all(frame.as_slice(), LEVEL)?;
+        compressed.write_all(&data)?;
+        frames.push(data.len() as u32);
+        compressed_size += data.len() as u64;
+    }
+    compressed.flush()?;
+
+    if !is_worth(size, compressed_size) {
+        return Ok(None);
+    }
+    Ok(Some(Compressed {
+        file: compressed,
+        frames,
+    }))
+}
+
+/// Content of one or several frames that follow each other.
+pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::decode_all(data)
+}
+
+fn is_worth(size: u64, compressed_size: u64) -> bool {
+    compressed_size * 100 <= size * (100 - MIN_SAVING)
+}
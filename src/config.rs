@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::serialization::from_str;
+
+/// The config file used if no other is given.
+const DEFAULT_CONFIG: &'static str = "fpfs.json";
+const DEFAULT_SESSION: &'static str = "fpfs.session";
+/// Saved Messages of the signed in account.
+const DEFAULT_CHAT: &'static str = "me";
+const DEFAULT_CACHE_DIR: &'static str = "fpfs_cache";
+const DEFAULT_CACHE_SIZE: u64 = 1024 * 1024 * 1024;
+const DEFAULT_REQUESTS_PER_MINUTE: usize = 300;
+
+/// Settings of one mounted filesystem.
+///
+/// They are read from the JSON config file (`--config`, `FPFS_CONFIG` or `fpfs.json`),
+///   env variables override the file and flags override everything:
+///
+/// | Field                 | Flag                    | Env variable               |
+/// |-----------------------|-------------------------|----------------------------|
+/// | `api_id`              | `--api-id`              | `TG_ID`                    |
+/// | `api_hash`            | `--api-hash`            | `TG_HASH`                  |
+/// | `session`             | `--session`             | `FPFS_SESSION`             |
+/// | `chat`                | `--chat`                | `FPFS_CHAT`                |
+/// | `cache_dir`           | `--cache-dir`           | `FPFS_CACHE_DIR`           |
+/// | `cache_size`          | `--cache-size`          | `FPFS_CACHE_SIZE`          |
+/// | `requests_per_minute` | `--requests-per-minute` | `FPFS_REQUESTS_PER_MINUTE` |
+/// | `encryption_key`      | `--encryption-key`      | `FPFS_ENCRYPTION_KEY`      |
+/// | `encryption_key_file` | `--encryption-key-file` | `FPFS_ENCRYPTION_KEY_FILE` |
+pub struct Config {
+    pub api_id: i32,
+    pub api_hash: String,
+    pub session: PathBuf,
+    /// The chat that keeps the filesystem: `me` for Saved Messages, `@username`
+    ///   or the id of a user, a group or a channel (`-100...` like in the bot API).
+    pub chat: String,
+    pub cache_dir: PathBuf,
+    /// Size of the cached content, in bytes.
+    pub cache_size: u64,
+    /// Limit of the requests to telegram, zero means no limit.
+    pub requests_per_minute: usize,
+    /// Passphrase the stored data is encrypted with, see `EncryptedChat`.
+    pub encryption_key: Option<String>,
+    /// File with the key, used instead of the passphrase.
+    pub encryption_key_file: Option<PathBuf>,
+}
+
+/// Content of the config file, every field may be missing.
+#[derive(Deserialize, Default)]
+struct ConfigFile {
+    api_id: Option<i32>,
+    api_hash: Option<String>,
+    session: Option<PathBuf>,
+    chat: Option<String>,
+    cache_dir: Option<PathBuf>,
+    cache_size: Option<u64>,
+    requests_per_minute: Option<usize>,
+    encryption_key: Option<String>,
+    encryption_key_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// `flags` are the command line flags without leading dashes, e.g. `api-id`.
+    pub fn load(flags: &HashMap<String, String>) -> Result<Config, String> {
+        let file = Config::read_file(flags)?;
+
+        let option = |flag: &str, variable: &str| -> Option<String> {
+            flags.get(flag).cloned().or_else(|| env::var(variable).ok())
+        };
+
+        let api_id = parse(option("api-id", "TG_ID"), "api id")?.or(file.api_id);
+        let api_hash = option("api-hash", "TG_HASH").or(file.api_hash);
+
+        Ok(Config {
+            api_id: api_id.ok_or("Api id is not set")?,
+            api_hash: api_hash.ok_or("Api hash is not set")?,
+            session: option("session", "FPFS_SESSION")
+                .map(PathBuf::from)
+                .or(file.session)
+                .unwrap_or(PathBuf::from(DEFAULT_SESSION)),
+            chat: option("chat", "FPFS_CHAT")
+                .or(file.chat)
+                .unwrap_or(DEFAULT_CHAT.to_string()),
+            cache_dir: option("cache-dir", "FPFS_CACHE_DIR")
+                .map(PathBuf::from)
+                .or(file.cache_dir)
+                .unwrap_or(PathBuf::from(DEFAULT_CACHE_DIR)),
+            cache_size: parse(option("cache-size", "FPFS_CACHE_SIZE"), "cache size")?
+                .or(file.cache_size)
+                .unwrap_or(DEFAULT_CACHE_SIZE),
+            requests_per_minute: parse(
+                option("requests-per-minute", "FPFS_REQUESTS_PER_MINUTE"),
+                "requests per minute",
+            )?
+            .or(file.requests_per_minute)
+            .unwrap_or(DEFAULT_REQUESTS_PER_MINUTE),
+            encryption_key: option("encryption-key", "FPFS_ENCRYPTION_KEY").or(file.encryption_key),
+            encryption_key_file: option("encryption-key-file", "FPFS_ENCRYPTION_KEY_FILE")
+                .map(PathBuf::from)
+                .or(file.encryption_key_file),
+        })
+    }
+
+    /// The secret the encryption key is derived from, `None` if the data isn't encrypted.
+    pub fn encryption_secret(&self) -> Result<Option<Vec<u8>>, String> {
+        if let Some(path) = &self.encryption_key_file {
+            return match fs::read(path) {
+                Ok(data) => Ok(Some(data)),
+                Err(e) => Err(format!("Can't read {}: {}", path.display(), e)),
+            };
+        }
+        Ok(self.encryption_key.as_ref().map(|x| x.as_bytes().to_vec()))
+    }
+
+    fn read_file(flags: &HashMap<String, String>) -> Result<ConfigFile, String> {
+        let path = flags
+            .get("config")
+            .cloned()
+            .or(env::var("FPFS_CONFIG").ok());
+
+        let path = match path {
+            Some(data) => PathBuf::from(data),
+            // The default config is optional
+            None if Path::new(DEFAULT_CONFIG).exists() => PathBuf::from(DEFAULT_CONFIG),
+            None => return Ok(ConfigFile::default()),
+        };
+
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        from_str(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+}
+
+fn parse<T: FromStr>(value: Option<String>, name: &str) -> Result<Option<T>, String> {
+    match value {
+        Some(data) => match data.parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(_) => Err(format!("Invalid {}: {}", name, data)),
+        },
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::serialization::{from_str, to_string};
+use crate::types::FileChunk;
+
+const INDEX_FILE: &'static str = "index.json";
+const METADATA_FILE: &'static str = "metadata.json";
+const META_ID_FILE: &'static str = "meta_id";
+const KEY_ID_FILE: &'static str = "key_id";
+const STORAGE_ID_FILE: &'static str = "storage_id";
+/// Followed by the id of the filesystem, the chunks of every filesystem are kept separately.
+const CHUNKS_FILE_PREFIX: &'static str = "chunks_";
+const CONTENT_DIR: &'static str = "content";
+
+/// Local directory that keeps the data between mounts.
+///
+/// Content of the chunks is stored by the id of the uploaded file, so it never becomes outdated:
+///   changed files are uploaded again and get new ids. The content is limited by `capacity` bytes,
+///   the least recently used chunks are removed first.
+///
+/// The metadata is saved on unmount and taken back on the next mount. The ids of the meta message
+///   and of the key record are kept as well, so the next mount doesn't search the chat for them,
+///   with the id of the filesystem to check that the meta message is the same.
+///   The known chunks are kept by the hash of their content, for every filesystem separately,
+///   see `TgConnection`.
+pub struct DiskCache {
+    directory: PathBuf,
+    capacity: u64,
+    index: Mutex<ContentIndex>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ContentIndex {
+    entries: HashMap<i64, ContentEntry>,
+    clock: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ContentEntry {
+    size: u64,
+    last_used: u64,
+}
+
+impl DiskCache {
+    pub fn open<P: AsRef<Path>>(directory: P, capacity: u64) -> io::Result<DiskCache> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(directory.join(CONTENT_DIR))?;
+
+        let index = fs::read_to_string(directory.join(INDEX_FILE))
+            .ok()
+            .and_then(|x| from_str(&x).ok())
+            .unwrap_or_default();
+
+        Ok(DiskCache {
+            directory,
+            capacity,
+            index: Mutex::new(index),
+        })
+    }
+
+    /// `size` bytes of the cached content starting from `offset`, `None` if it's not cached.
+    pub fn read(&self, file_id: i64, offset: u64, size: u64) -> Option<Vec<u8>> {
+        {
+            let mut index = self.index.lock().unwrap();
+            index.clock += 1;
+            let clock = index.clock;
+            index.entries.get_mut(&file_id)?.last_used = clock;
+        }
+
+        let mut file = File::open(self.content_path(file_id)).ok()?;
+        file.seek(SeekFrom::Start(offset)).ok()?;
+        let mut data = vec![];
+        file.take(size).read_to_end(&mut data).ok()?;
+        Some(data)
+    }
+
+    /// Keep the content of the file, older content is removed if there is no space.
+    pub fn insert(&self, file_id: i64, path: &Path) {
+        let size = match fs::metadata(path) {
+            Ok(data) => data.len(),
+            Err(_) => return,
+        };
+        if size > self.capacity {
+            return;
+        }
+
+        let mut index = self.index.lock().unwrap();
+        if fs::copy(path, self.content_path(file_id)).is_err() {
+            return;
+        }
+        index.clock += 1;
+        let entry = ContentEntry {
+            size,
+            last_used: index.clock,
+        };
+        index.entries.insert(file_id, entry);
+
+        let mut total: u64 = index.entries.values().map(|x| x.size).sum();
+        while total > self.capacity {
+            let oldest = index
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(&id, entry)| (id, entry.size));
+            if let Some((id, size)) = oldest {
+                index.entries.remove(&id);
+                let _ = fs::remove_file(self.content_path(id));
+                total -= size;
+            } else {
+                break;
+            }
+        }
+
+        // The content is kept even if the index isn't saved, it's only used by this mount then
+        let _ = self.save_index(&index);
+    }
+
+    pub fn insert_data(&self, file_id: i64, data: &[u8]) {
+        let path = self.directory.join(format!("{}.tmp", file_id));
+        if fs::write(&path, data).is_ok() {
+            self.insert(file_id, &path);
+        }
+        let _ = fs::remove_file(path);
+    }
+
+    /// Take the metadata saved by the previous mount. It's removed from the disk,
+    ///   so it's not used again if this mount doesn't end properly.
+    pub fn take_metadata<T: DeserializeOwned>(&self) -> Option<T> {
+        let path = self.directory.join(METADATA_FILE);
+        let text = fs::read_to_string(&path).ok()?;
+        let _ = fs::remove_file(path);
+        from_str(&text).ok()
+    }
+
+    pub fn save_metadata<T: Serialize>(&self, metadata: &T) -> io::Result<()> {
+        let text = to_string(metadata)?;
+        fs::write(self.directory.join(METADATA_FILE), text)?;
+
+        self.save_index(&self.index.lock().unwrap())
+    }
+
+    /// Id of the meta message of the filesystem, see `TgConnection`.
+    pub fn meta_id(&self) -> Option<i32> {
+        self.read_id(META_ID_FILE)
+    }
+
+    /// `None` removes the saved id, e.g. when the filesystem is removed from the chat.
+    pub fn save_meta_id(&self, id: Option<i32>) -> io::Result<()> {
+        self.save_id(META_ID_FILE, id)
+    }
+
+    /// Id of the key record, see `EncryptedChat`.
+    pub fn key_id(&self) -> Option<i32> {
+        self.read_id(KEY_ID_FILE)
+    }
+
+    pub fn save_key_id(&self, id: i32) -> io::Result<()> {
+        self.save_id(KEY_ID_FILE, Some(id))
+    }
+
+    /// Id of the filesystem the meta message belongs to, see `MetaMessage::id`.
+    pub fn storage_id(&self) -> Option<u64> {
+        self.read_id(STORAGE_ID_FILE)
+    }
+
+    pub fn save_storage_id(&self, id: u64) -> io::Result<()> {
+        self.save_id(STORAGE_ID_FILE, Some(id))
+    }
+
+    /// Chunks of the filesystem `storage` by the hash of their content.
+    pub fn content_index(&self, storage: u64) -> HashMap<String, FileChunk> {
+        fs::read_to_string(self.chunks_path(storage))
+            .ok()
+            .and_then(|x| from_str(&x).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save_content_index(
+        &self,
+        storage: u64,
+        chunks: &HashMap<String, FileChunk>,
+    ) -> io::Result<()> {
+        let text = to_string(chunks)?;
+        fs::write(self.chunks_path(storage), text)
+    }
+
+    fn read_id<T: FromStr>(&self, file: &str) -> Option<T> {
+        let text = fs::read_to_string(self.directory.join(file)).ok()?;
+        text.trim().parse().ok()
+    }
+
+    fn save_id<T: ToString>(&self, file: &str, id: Option<T>) -> io::Result<()> {
+        let path = self.directory.join(file);
+        match id {
+            Some(data) => fs::write(path, data.to_string()),
+            None => match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    fn save_index(&self, index: &ContentIndex) -> io::Result<()> {
+        let text = to_string(index)?;
+        fs::write(self.directory.join(INDEX_FILE), text)
+    }
+
+    fn chunks_path(&self, storage: u64) -> PathBuf {
+        let name = format!("{}{}.json", CHUNKS_FILE_PREFIX, storage);
+        self.directory.join(name)
+    }
+
+    fn content_path(&self, file_id: i64) -> PathBuf {
+        self.directory.join(CONTENT_DIR).join(file_id.to_string())
+    }
+}
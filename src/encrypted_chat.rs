@@ -0,0 +1,513 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use async_trait::async_trait;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use scrypt::{scrypt, ScryptParams};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::chat::{Chat, ChatMessage, EditError};
+use crate::error::{FsError, FsResult};
+use crate::serialization::{from_prefixed_str, to_prefixed_string};
+use crate::types::FpfsInputFile;
+
+const KEY_CONSTANT: &'static str = "[KEY]";
+const ENCRYPTED_CONSTANT: &'static str = "[ENC]";
+
+/// The key record keeps this text encrypted with the key, so a wrong key is noticed.
+const KEY_CHECK: &'static str = "fpfs key check";
+
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+
+/// scrypt parameters recommended for interactive logins: N = 2^15, r = 8, p = 1.
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// Files are encrypted by blocks of this size, so a range of the file can be decrypted
+///   without downloading all of it.
+const BLOCK_SIZE: usize = 64 * 1024;
+const ENCRYPTED_BLOCK_SIZE: usize = BLOCK_SIZE + TAG_SIZE;
+
+/// An encrypted file starts with the random prefix of the nonces, the nonce of a block is
+///   the prefix, the number of the block and a flag of the last block (the STREAM construction).
+///   The last block is shorter than `BLOCK_SIZE`, even empty, so a file cut at any place
+///   can't be decrypted.
+const NONCE_PREFIX_SIZE: usize = NONCE_SIZE - 9;
+
+/// Encrypted text is written with CJK ideographs starting from this one, 14 bits per character.
+const FIRST_CHAR: u32 = 0x4E00;
+const BITS_PER_CHAR: u32 = 14;
+
+/// `Chat` that encrypts everything stored in the wrapped chat: texts of the messages and
+///   the uploaded files. XChaCha20-Poly1305 is used with the key derived from the secret by scrypt.
+///
+/// The salt of the key is kept in the `[KEY]` message together with a text encrypted with the key,
+///   so a wrong secret fails `unlock` instead of showing an empty filesystem.
+///
+/// Messages that can't be decrypted, e.g. other messages of Saved Messages, are treated as missing.
+///   Without a secret (`plain`) everything is passed to the wrapped chat as is.
+pub struct EncryptedChat<C: Chat> {
+    chat: C,
+    cipher: Option<Cipher>,
+}
+
+struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyRecord {
+    salt: Vec<u8>,
+    /// `KEY_CHECK` encrypted with the key.
+    check: String,
+}
+
+impl<C: Chat> EncryptedChat<C> {
+    pub fn plain(chat: C) -> EncryptedChat<C> {
+        EncryptedChat { chat, cipher: None }
+    }
+
+    /// The chat without encryption, `FsError::KeyRequired` if it has a key record,
+    ///   so an encrypted filesystem isn't taken for a missing one.
+    ///
+    /// `known_meta` is the id of the meta message of the filesystem, `is_meta` checks that
+    ///   the message is the meta message of this filesystem. If it or a pinned message passes
+    ///   the check as is, the filesystem isn't encrypted and the chat isn't searched
+    ///   for the record. The record is pinned by `unlock`, so it's checked first.
+    pub async fn open_plain(
+        chat: C,
+        known_meta: Option<i32>,
+        is_meta: &(dyn Fn(&str) -> bool + Sync),
+    ) -> FsResult<EncryptedChat<C>> {
+        let is_record = |text: &str| text.starts_with(KEY_CONSTANT);
+        if chat.find_pinned_message(&is_record).await?.is_some() {
+            return Err(FsError::KeyRequired);
+        }
+
+        if let Some(id) = known_meta {
+            if let Some(message) = chat.get_messages(&[id]).await?.remove(0) {
+                if is_meta(&message.text) {
+                    return Ok(EncryptedChat::plain(chat));
+                }
+            }
+        }
+        if chat.find_pinned_message(is_meta).await?.is_some() {
+            return Ok(EncryptedChat::plain(chat));
+        }
+
+        let record = chat.find_message(&is_record).await?;
+        match record {
+            Some(_) => Err(FsError::KeyRequired),
+            None => Ok(EncryptedChat::plain(chat)),
+        }
+    }
+
+    /// Derive the key from the secret and check it against the key record of the chat.
+    ///   The record is created if the chat doesn't have one yet.
+    ///
+    /// `known_record` is the id of the key record returned by the previous unlock. If it's
+    ///   unknown, the record is looked up among the pinned messages, the chat is searched
+    ///   only if it isn't pinned. Returns the chat and the id of the record.
+    pub async fn unlock(
+        chat: C,
+        secret: &[u8],
+        known_record: Option<i32>,
+    ) -> FsResult<(EncryptedChat<C>, i32)> {
+        let is_record = |text: &str| text.starts_with(KEY_CONSTANT);
+        let mut record = None;
+        if let Some(id) = known_record {
+            record = chat.get_messages(&[id]).await?.remove(0);
+        }
+        if !record.as_ref().map_or(false, |x| is_record(&x.text)) {
+            record = chat.find_pinned_message(&is_record).await?;
+        }
+        if record.is_none() {
+            record = chat.find_message(&is_record).await?;
+            if let Some(message) = &record {
+                pin_record(&chat, message.id).await;
+            }
+        }
+
+        if let Some(message) = record {
+            let key_record: KeyRecord = from_prefixed_str(KEY_CONSTANT, &message.text)?;
+            let cipher = Cipher::derive(secret, &key_record.salt);
+            if cipher.decrypt_text(&key_record.check).as_deref() != Some(KEY_CHECK) {
+                return Err(FsError::WrongKey);
+            }
+            let chat = EncryptedChat {
+                chat,
+                cipher: Some(cipher),
+            };
+            return Ok((chat, message.id));
+        }
+
+        let salt: [u8; SALT_SIZE] = rand::random();
+        let cipher = Cipher::derive(secret, &salt);
+        let key_record = KeyRecord {
+            salt: salt.to_vec(),
+            check: cipher.encrypt_text(KEY_CHECK),
+        };
+        let id = chat
+            .send_message(to_prefixed_string(KEY_CONSTANT, &key_record)?, None)
+            .await?;
+        pin_record(&chat, id).await;
+
+        let chat = EncryptedChat {
+            chat,
+            cipher: Some(cipher),
+        };
+        Ok((chat, id))
+    }
+
+    fn encrypt_text(&self, text: String) -> String {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt_text(&text),
+            None => text,
+        }
+    }
+
+    /// `None` if the message isn't encrypted with our key.
+    fn decrypt_message(&self, message: ChatMessage) -> Option<ChatMessage> {
+        match &self.cipher {
+            Some(cipher) => Some(ChatMessage {
+                id: message.id,
+                text: cipher.decrypt_text(&message.text)?,
+            }),
+            None => Some(message),
+        }
+    }
+}
+
+impl Cipher {
+    fn derive(secret: &[u8], salt: &[u8]) -> Cipher {
+        let params = ScryptParams::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P).unwrap();
+        let mut key = [0u8; 32];
+        scrypt(secret, salt, &params, &mut key).unwrap();
+
+        Cipher {
+            aead: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        }
+    }
+
+    /// Length of the encrypted text in characters, see `encode`.
+    fn encrypted_length(text: &str) -> usize {
+        let size = NONCE_SIZE + text.len() + TAG_SIZE;
+        let chars = (size * 8 + BITS_PER_CHAR as usize - 1) / BITS_PER_CHAR as usize;
+        ENCRYPTED_CONSTANT.len() + 1 + chars
+    }
+
+    fn encrypt_text(&self, text: &str) -> String {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let mut data = nonce.to_vec();
+        data.extend(
+            self.aead
+                .encrypt(XNonce::from_slice(&nonce), text.as_bytes())
+                .unwrap(),
+        );
+        format!("{}{}", ENCRYPTED_CONSTANT, encode(&data))
+    }
+
+    fn decrypt_text(&self, text: &str) -> Option<String> {
+        if !text.starts_with(ENCRYPTED_CONSTANT) {
+            return None;
+        }
+        let data = decode(&text[ENCRYPTED_CONSTANT.len()..])?;
+        if data.len() < NONCE_SIZE {
+            return None;
+        }
+
+        let (nonce, encrypted) = data.split_at(NONCE_SIZE);
+        let decrypted = self
+            .aead
+            .decrypt(XNonce::from_slice(nonce), encrypted)
+            .ok()?;
+        String::from_utf8(decrypted).ok()
+    }
+
+    /// Encrypted copy of the file.
+    fn encrypt_file(&self, path: &Path) -> io::Result<NamedTempFile> {
+        let mut source = File::open(path)?;
+        let mut encrypted = NamedTempFile::new()?;
+
+        let prefix: [u8; NONCE_PREFIX_SIZE] = rand::random();
+        encrypted.write_all(&prefix)?;
+
+        let mut block = vec![0u8; BLOCK_SIZE];
+        let mut index = 0;
+        loop {
+            let size = read_block(&mut source, &mut block)?;
+            let last = size < BLOCK_SIZE;
+
+            let nonce = block_nonce(&prefix, index, last);
+            let data = self
+                .aead
+                .encrypt(XNonce::from_slice(&nonce), &block[..size])
+                .unwrap();
+            encrypted.write_all(&data)?;
+
+            if last {
+                break;
+            }
+            index += 1;
+        }
+
+        encrypted.flush()?;
+        Ok(encrypted)
+    }
+
+    /// Decrypt the blocks of the file starting from the block `first`.
+    ///   The data that reaches the end of the file must end with the last block.
+    fn decrypt_blocks(
+        &self,
+        prefix: &[u8],
+        first: u64,
+        data: &[u8],
+        to_end: bool,
+    ) -> FsResult<Vec<u8>> {
+        if to_end && data.len() % ENCRYPTED_BLOCK_SIZE == 0 {
+            return Err(undecryptable());
+        }
+
+        let mut decrypted = Vec::with_capacity(data.len());
+        for (index, block) in data.chunks(ENCRYPTED_BLOCK_SIZE).enumerate() {
+            let last = block.len() < ENCRYPTED_BLOCK_SIZE;
+            let nonce = block_nonce(prefix, first + index as u64, last);
+            let part = self
+                .aead
+                .decrypt(XNonce::from_slice(&nonce), block)
+                .map_err(|_| undecryptable())?;
+            decrypted.extend(part);
+        }
+        Ok(decrypted)
+    }
+}
+
+/// The record is pinned, so a mount without the cache finds it without searching the whole chat.
+async fn pin_record<C: Chat>(chat: &C, id: i32) {
+    if let Err(e) = chat.pin_message(id).await {
+        log::warn!("Can't pin the key record: {}", e);
+    }
+}
+
+fn undecryptable() -> FsError {
+    FsError::Corrupted("The file can't be decrypted".to_string())
+}
+
+fn block_nonce(prefix: &[u8], index: u64, last: bool) -> Vec<u8> {
+    let mut nonce = prefix.to_vec();
+    nonce.extend_from_slice(&index.to_le_bytes());
+    nonce.push(last as u8);
+    nonce
+}
+
+/// Read until the block is full or the file ends.
+fn read_block(file: &mut File, block: &mut [u8]) -> io::Result<usize> {
+    let mut size = 0;
+    while size < block.len() {
+        match file.read(&mut block[size..])? {
+            0 => break,
+            read => size += read,
+        }
+    }
+    Ok(size)
+}
+
+/// Telegram limits the length of a message in characters, so the encrypted bytes are packed
+///   into CJK ideographs, 14 bits each: the text is shorter than the bytes, while base64 would make
+///   it a third longer. The first character is the amount of padding bytes to drop.
+fn encode(data: &[u8]) -> String {
+    let chars = (data.len() as u32 * 8 + BITS_PER_CHAR - 1) / BITS_PER_CHAR;
+    let padding = (chars * BITS_PER_CHAR / 8) as usize - data.len();
+
+    let mut text = padding.to_string();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        if bits >= BITS_PER_CHAR {
+            bits -= BITS_PER_CHAR;
+            text.push(ideograph(buffer >> bits));
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    if bits > 0 {
+        text.push(ideograph(buffer << (BITS_PER_CHAR - bits)));
+    }
+    text
+}
+
+fn ideograph(value: u32) -> char {
+    std::char::from_u32(FIRST_CHAR + value).unwrap()
+}
+
+fn decode(text: &str) -> Option<Vec<u8>> {
+    let mut chars = text.chars();
+    let padding = chars.next()?.to_digit(10)? as usize;
+
+    let mut data = vec![];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in chars {
+        let value = (c as u32).checked_sub(FIRST_CHAR)?;
+        if value >= 1 << BITS_PER_CHAR {
+            return None;
+        }
+
+        buffer = (buffer << BITS_PER_CHAR) | value;
+        bits += BITS_PER_CHAR;
+        while bits >= 8 {
+            bits -= 8;
+            data.push((buffer >> bits) as u8);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+
+    let size = data.len().checked_sub(padding)?;
+    data.truncate(size);
+    Some(data)
+}
+
+#[async_trait]
+impl<C: Chat> Chat for EncryptedChat<C> {
+    async fn send_message(&self, text: String, file: Option<FpfsInputFile>) -> FsResult<i32> {
+        self.chat.send_message(self.encrypt_text(text), file).await
+    }
+
+    async fn edit_message(
+        &self,
+        id: i32,
+        text: String,
+        file: Option<FpfsInputFile>,
+    ) -> Result<(), EditError> {
+        self.chat
+            .edit_message(id, self.encrypt_text(text), file)
+            .await
+    }
+
+    async fn delete_messages(&self, ids: &[i32]) -> FsResult<()> {
+        self.chat.delete_messages(ids).await
+    }
+
+    async fn get_messages(&self, ids: &[i32]) -> FsResult<Vec<Option<ChatMessage>>> {
+        let messages = self.chat.get_messages(ids).await?;
+        Ok(messages
+            .into_iter()
+            .map(|x| x.and_then(|message| self.decrypt_message(message)))
+            .collect())
+    }
+
+    async fn find_message(
+        &self,
+        filter: &(dyn Fn(&str) -> bool + Sync),
+    ) -> FsResult<Option<ChatMessage>> {
+        let cipher = match &self.cipher {
+            Some(data) => data,
+            None => return self.chat.find_message(filter).await,
+        };
+
+        let message = self
+            .chat
+            .find_message(&|text| cipher.decrypt_text(text).map_or(false, |x| filter(&x)))
+            .await?;
+        Ok(message.and_then(|x| self.decrypt_message(x)))
+    }
+
+    async fn pin_message(&self, id: i32) -> FsResult<()> {
+        self.chat.pin_message(id).await
+    }
+
+    async fn find_pinned_message(
+        &self,
+        filter: &(dyn Fn(&str) -> bool + Sync),
+    ) -> FsResult<Option<ChatMessage>> {
+        let cipher = match &self.cipher {
+            Some(data) => data,
+            None => return self.chat.find_pinned_message(filter).await,
+        };
+
+        let message = self
+            .chat
+            .find_pinned_message(&|text| cipher.decrypt_text(text).map_or(false, |x| filter(&x)))
+            .await?;
+        Ok(message.and_then(|x| self.decrypt_message(x)))
+    }
+
+    async fn upload_file(&self, path: &Path) -> FsResult<FpfsInputFile> {
+        let cipher = match &self.cipher {
+            Some(data) => data,
+            None => return self.chat.upload_file(path).await,
+        };
+
+        // The temporary file is removed when it's dropped, after the upload
+        let encrypted = cipher.encrypt_file(path)?;
+        self.chat.upload_file(encrypted.path()).await
+    }
+
+    async fn download_media(&self, id: i32) -> FsResult<Vec<u8>> {
+        let data = self.chat.download_media(id).await?;
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return Ok(data),
+        };
+
+        if data.len() < NONCE_PREFIX_SIZE {
+            return Err(undecryptable());
+        }
+        let (prefix, blocks) = data.split_at(NONCE_PREFIX_SIZE);
+        cipher.decrypt_blocks(prefix, 0, blocks, true)
+    }
+
+    async fn download_range(&self, id: i32, offset: u64, size: u64) -> FsResult<Vec<u8>> {
+        let cipher = match &self.cipher {
+            Some(data) => data,
+            None => return self.chat.download_range(id, offset, size).await,
+        };
+        if size == 0 {
+            return Ok(vec![]);
+        }
+
+        // Only the blocks covering the range are downloaded, and the nonce prefix
+        let first = offset / BLOCK_SIZE as u64;
+        let last = (offset + size - 1) / BLOCK_SIZE as u64;
+        let start = NONCE_PREFIX_SIZE as u64 + first * ENCRYPTED_BLOCK_SIZE as u64;
+        let length = (last - first + 1) * ENCRYPTED_BLOCK_SIZE as u64;
+
+        let (prefix, blocks) = if first == 0 {
+            let mut prefix = self.chat.download_range(id, 0, start + length).await?;
+            let blocks = prefix.split_off(NONCE_PREFIX_SIZE.min(prefix.len()));
+            (prefix, blocks)
+        } else {
+            let prefix = self
+                .chat
+                .download_range(id, 0, NONCE_PREFIX_SIZE as u64)
+                .await?;
+            let blocks = self.chat.download_range(id, start, length).await?;
+            (prefix, blocks)
+        };
+        if prefix.len() < NONCE_PREFIX_SIZE {
+            return Err(undecryptable());
+        }
+
+        // Less than asked is downloaded only at the end of the file
+        let to_end = (blocks.len() as u64) < length;
+        let data = cipher.decrypt_blocks(&prefix, first, &blocks, to_end)?;
+        let skip = (offset - first * BLOCK_SIZE as u64) as usize;
+        let start = skip.min(data.len());
+        let end = (skip + size as usize).min(data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    fn text_length(&self, text: &str) -> usize {
+        match &self.cipher {
+            Some(_) => Cipher::encrypted_length(text),
+            None => self.chat.text_length(text),
+        }
+    }
+}
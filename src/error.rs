@@ -0,0 +1,116 @@
+use std::fmt;
+use std::io;
+
+use grammers_mtproto::mtp::RpcError;
+use grammers_mtsender::InvocationError;
+use libc::{EACCES, EAGAIN, EBUSY, EIO, ENOENT, ENOSPC, ENOTEMPTY, EPROTO};
+
+use crate::types::VERSION;
+
+/// Failure of the storage. It fails only the request that caused it, the filesystem keeps working.
+#[derive(Debug)]
+pub enum FsError {
+    /// The inode, the message or its attached file doesn't exist.
+    NotFound,
+    /// Telegram doesn't accept that much data, e.g. the message or the uploaded file is too large.
+    NoSpace(String),
+    /// Telegram asks to wait this amount of seconds before the next request (`FLOOD_WAIT_X`).
+    FloodWait(u32),
+    /// Any other error returned by telegram.
+    Rpc(String),
+    /// The request didn't reach telegram or the answer was lost.
+    Network(String),
+    /// Another mount keeps changing the same message, so the change can't be applied.
+    Conflict(String),
+    /// The directory can't be removed while it has children.
+    NotEmpty,
+    /// The encryption key doesn't match the key the filesystem was created with.
+    WrongKey,
+    /// The filesystem is encrypted, but no encryption key is given.
+    KeyRequired,
+    /// A stored message can't be parsed.
+    Corrupted(String),
+    /// The filesystem is stored in another format, e.g. by an older version of fpfs.
+    Unsupported(String),
+    /// Failure of a local file, e.g. the temporary copy of the written file.
+    Io(io::Error),
+}
+
+pub type FsResult<T> = Result<T, FsError>;
+
+impl FsError {
+    /// Error returned by telegram, `value` is the number in the names like `FLOOD_WAIT_X`.
+    pub fn from_rpc(name: &str, value: Option<u32>) -> FsError {
+        match name {
+            "MESSAGE_ID_INVALID" | "MESSAGE_EMPTY" | "FILE_REFERENCE_EXPIRED" => FsError::NotFound,
+            "FLOOD_WAIT" | "SLOWMODE_WAIT" => FsError::FloodWait(value.unwrap_or(0)),
+            "MESSAGE_TOO_LONG"
+            | "FILE_PARTS_INVALID"
+            | "FILE_PART_SIZE_INVALID"
+            | "FILE_PART_TOO_BIG" => FsError::NoSpace(name.to_string()),
+            _ => FsError::Rpc(name.to_string()),
+        }
+    }
+
+    /// The errno the request fails with.
+    pub fn errno(&self) -> i32 {
+        match self {
+            FsError::NotFound => ENOENT,
+            FsError::NoSpace(_) => ENOSPC,
+            FsError::FloodWait(_) => EAGAIN,
+            FsError::Conflict(_) => EBUSY,
+            FsError::NotEmpty => ENOTEMPTY,
+            FsError::WrongKey | FsError::KeyRequired => EACCES,
+            FsError::Unsupported(_) => EPROTO,
+            FsError::Rpc(_) | FsError::Network(_) | FsError::Corrupted(_) => EIO,
+            FsError::Io(e) => e.raw_os_error().unwrap_or(EIO),
+        }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::NotFound => write!(f, "Not found"),
+            FsError::NoSpace(name) => write!(f, "Too much data: {}", name),
+            FsError::FloodWait(seconds) => write!(f, "Flood wait for {} seconds", seconds),
+            FsError::Rpc(name) => write!(f, "Telegram error: {}", name),
+            FsError::Network(message) => write!(f, "Network error: {}", message),
+            FsError::Conflict(message) => write!(f, "Conflicting change: {}", message),
+            FsError::NotEmpty => write!(f, "Directory not empty"),
+            FsError::WrongKey => write!(f, "Wrong encryption key"),
+            FsError::KeyRequired => write!(
+                f,
+                "The filesystem is encrypted, set encryption_key or encryption_key_file"
+            ),
+            FsError::Corrupted(message) => write!(f, "Corrupted message: {}", message),
+            FsError::Unsupported(version) => write!(
+                f,
+                "The filesystem is stored in format {}, this version of fpfs reads only {}",
+                version, VERSION
+            ),
+            FsError::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl From<InvocationError> for FsError {
+    fn from(e: InvocationError) -> Self {
+        match e {
+            InvocationError::Rpc(RpcError { name, value, .. }) => FsError::from_rpc(&name, value),
+            e => FsError::Network(e.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for FsError {
+    fn from(e: io::Error) -> Self {
+        FsError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for FsError {
+    fn from(e: serde_json::Error) -> Self {
+        FsError::Corrupted(e.to_string())
+    }
+}
@@ -1,17 +1,26 @@
+use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::io::Write;
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use fuse::{
     FileAttr, FileType, Filesystem, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory,
     ReplyEmpty, ReplyEntry, ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXTimes, ReplyXattr,
     Request,
 };
-use libc::{ENOENT, ENOSYS, ERANGE};
+use libc::{EBADF, EINVAL, EISDIR, ENOSYS, ENOTDIR, ERANGE};
 use tempfile::NamedTempFile;
 use time::Timespec;
 use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
 
-use crate::tg::TgConnection;
+use crate::cache::{CacheSnapshot, InodeCache};
+use crate::compression;
+use crate::disk_cache::DiskCache;
+use crate::error::{FsError, FsResult};
+use crate::storage::StorageBackend;
 use crate::types::FileLink;
 use std::path::Path;
 
@@ -26,6 +35,17 @@ use std::path::Path;
 
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
 
+/// Amount of inodes kept in the cache.
+const CACHE_SIZE: usize = 100_000;
+
+/// A file is created with a new inode at most this amount of times
+///   when other mounts take the same inodes.
+const MAX_CREATE_ATTEMPTS: u32 = 3;
+
+/// The stored content is copied to a local file by parts of this size,
+///   so a large file is never kept in memory.
+const COPY_PART_SIZE: u64 = 4 * 1024 * 1024;
+
 const UNIX_EPOCH: Timespec = Timespec { sec: 0, nsec: 0 };
 
 const HELLO_DIR_ATTR: FileAttr = FileAttr {
@@ -62,122 +82,408 @@ const HELLO_TXT_ATTR: FileAttr = FileAttr {
     flags: 0,
 };
 
-pub struct Fpfs {
-    connection: TgConnection,
-    files_cache: Option<Vec<FileLink>>,
-    cache_ino: u64,
+/// Every request is handled in a separate task of `runtime` and replied from there,
+///   so a slow download doesn't block other requests.
+pub struct Fpfs<B: StorageBackend> {
+    /// All the requests to the storage are executed in this runtime.
+    runtime: Arc<Runtime>,
+    state: Arc<FpfsState<B>>,
+    /// The inode cache is saved here when the filesystem is unmounted.
+    disk_cache: Option<Arc<DiskCache>>,
 }
 
-impl Fpfs {
-    pub fn new(connection: TgConnection) -> Fpfs {
-        return Fpfs {
+/// The part of `Fpfs` shared between the tasks.
+struct FpfsState<B: StorageBackend> {
+    connection: B,
+    cache: Mutex<InodeCache>,
+    open_files: Mutex<HashMap<u64, Arc<OpenFile>>>,
+    /// Local copies of the files by inode, which failed to upload when their handle was released.
+    ///   The next handle of the file continues with the copy, so its flush uploads the changes.
+    unsaved: Mutex<HashMap<u64, NamedTempFile>>,
+    next_fh: AtomicU64,
+    /// Owner shown for all the files instead of the stored one.
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+/// A file opened by `open` or `create`, identified by `fh`.
+struct OpenFile {
+    ino: u64,
+    /// Local copy of the file with the changes that are not uploaded yet.
+    ///   It's created on the first write and uploaded on `flush`, `fsync` or `release`.
+    buffer: Mutex<Option<NamedTempFile>>,
+}
+
+impl<B: StorageBackend> Fpfs<B> {
+    /// Use the runtime the connection was created in, e.g. the one that runs the telegram client.
+    pub fn with_runtime(connection: B, runtime: Arc<Runtime>) -> Fpfs<B> {
+        let state = FpfsState {
             connection,
-            files_cache: None,
-            cache_ino: 0,
+            cache: Mutex::new(InodeCache::new(CACHE_SIZE)),
+            open_files: Mutex::new(HashMap::new()),
+            unsaved: Mutex::new(HashMap::new()),
+            next_fh: AtomicU64::new(1),
+            uid: None,
+            gid: None,
+        };
+        return Fpfs {
+            runtime,
+            state: Arc::new(state),
+            disk_cache: None,
         };
     }
 
-    fn get_cache(&mut self, directory: &u64) -> &Vec<FileLink> {
-        self.init_cache(directory);
-        self.files_cache.as_ref().unwrap()
-    }
-
-    fn get_cur_cache(&mut self) -> &Vec<FileLink> {
-        let ino = self.cache_ino;
-        self.init_cache(&ino);
-        self.files_cache.as_ref().unwrap()
+    /// Start with the inodes cached by the previous mount. The links are checked again
+    ///   when the files are opened.
+    pub fn with_disk_cache(mut self, disk_cache: Arc<DiskCache>) -> Fpfs<B> {
+        self.disk_cache = Some(disk_cache);
+        self
     }
 
-    fn get_cur_cache_mut(&mut self) -> &mut Vec<FileLink> {
-        let ino = self.cache_ino;
-        self.init_cache(&ino);
-        self.files_cache.as_mut().unwrap()
+    /// Show all the files as owned by the given user and group, e.g. the one who mounted them.
+    pub fn with_owner(mut self, uid: Option<u32>, gid: Option<u32>) -> Fpfs<B> {
+        let state = Arc::get_mut(&mut self.state).expect("The filesystem is already mounted");
+        state.uid = uid;
+        state.gid = gid;
+        self
     }
 
-    fn get_cache_mut(&mut self, directory: &u64) -> &mut Vec<FileLink> {
-        self.init_cache(directory);
-        self.files_cache.as_mut().unwrap()
+    fn make_attr(size: u64, ino: u64) -> FileAttr {
+        FileAttr {
+            size,
+            ino,
+            ..HELLO_TXT_ATTR
+        }
     }
 
-    fn init_cache(&mut self, directory: &u64) {
-        if self.files_cache.is_none() || self.cache_ino != *directory {
-            let files = Runtime::new()
-                .unwrap()
-                .block_on(self.connection.get_directory_files(directory));
-            self.files_cache = Some(files);
-            self.cache_ino = directory.clone()
+    fn make_dir_attr(ino: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            ..HELLO_DIR_ATTR
         }
     }
 
-    fn make_attr(size: u64, ino: u64) -> FileAttr {
+    /// The size of the symlink is the length of its target.
+    fn make_symlink_attr(size: u64, ino: u64) -> FileAttr {
         FileAttr {
             size,
             ino,
+            kind: FileType::Symlink,
+            perm: 0o777,
             ..HELLO_TXT_ATTR
         }
     }
 
-    fn make_dir_attr(ino: u64) -> FileAttr {
+    #[allow(dead_code)]
+    pub async fn remove_meta(&self) -> FsResult<()> {
+        self.state.connection.cleanup().await
+    }
+}
+
+impl<B: StorageBackend + 'static> Fpfs<B> {
+    /// Run the handler in the runtime, the handler is responsible for the reply.
+    fn spawn<F, T>(&self, handler: F)
+    where
+        F: FnOnce(Arc<FpfsState<B>>) -> T,
+        T: Future<Output = ()> + Send + 'static,
+    {
+        self.runtime.spawn(handler(self.state.clone()));
+    }
+}
+
+/// The name as a string. The names are stored as strings, so the other names are rejected
+///   with `EINVAL` and the handler returns.
+macro_rules! utf8_name {
+    ($name:expr, $reply:expr) => {
+        match $name.to_str() {
+            Some(data) => data.to_string(),
+            None => {
+                $reply.error(EINVAL);
+                return;
+            }
+        }
+    };
+}
+
+impl<B: StorageBackend> FpfsState<B> {
+    /// Children of the directory, they are requested from the storage only if not cached.
+    async fn children(&self, directory: u64) -> FsResult<Vec<FileLink>> {
+        if let Some(files) = self.cache.lock().await.children(directory) {
+            return Ok(files);
+        }
+
+        let files = self.connection.get_directory_files(&directory).await?;
+        self.cache
+            .lock()
+            .await
+            .set_children(directory, files.clone());
+        Ok(files)
+    }
+
+    /// `FsError::NotFound` if the directory has no such child.
+    async fn find_child(&self, parent: u64, name: &str) -> FsResult<FileLink> {
+        if let Some(found) = self.cache.lock().await.find_child(parent, name) {
+            return found.ok_or(FsError::NotFound);
+        }
+
+        let files = self.connection.get_directory_files(&parent).await?;
+        let found = files.iter().find(|x| x.name == name).cloned();
+        self.cache.lock().await.set_children(parent, files);
+        found.ok_or(FsError::NotFound)
+    }
+
+    /// The attributes as they are replied to the kernel.
+    fn shown_attr(&self, attr: &FileAttr) -> FileAttr {
         FileAttr {
+            uid: self.uid.unwrap_or(attr.uid),
+            gid: self.gid.unwrap_or(attr.gid),
+            ..*attr
+        }
+    }
+
+    async fn update_cached<F: FnOnce(&mut FileLink)>(&self, ino: u64, f: F) {
+        self.cache.lock().await.update(ino, f);
+    }
+
+    async fn next_ino(&self) -> FsResult<u64> {
+        self.connection.get_and_inc_ino().await
+    }
+
+    /// Create an inode with `create`, which gets the new inode and returns its attributes.
+    ///   Another mount may take the same inode at the same time, then the creation fails
+    ///   with `FsError::Conflict` and is repeated with the next inode from a new lease.
+    async fn create_inode<F, Fut>(&self, create: F) -> FsResult<FileAttr>
+    where
+        F: Fn(u64) -> Fut,
+        Fut: Future<Output = FsResult<FileAttr>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let ino = self.next_ino().await?;
+            match create(ino).await {
+                Err(FsError::Conflict(e)) if attempt < MAX_CREATE_ATTEMPTS => {
+                    log::warn!("{}, creating with another inode", e);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Replace the content of the file and update its size in the cache.
+    async fn store_content(&self, ino: u64, content: &NamedTempFile) -> FsResult<()> {
+        self.connection.write_to_file(content, ino).await?;
+
+        let size = content.as_file().metadata()?.len();
+        self.set_cached_size(ino, size).await;
+        Ok(())
+    }
+
+    /// Local copy of the stored content cut or extended with zeros to `size`.
+    ///   Nothing is downloaded for the empty copy, e.g. when the file is opened with `O_TRUNC`.
+    async fn copy_content(&self, ino: u64, size: u64) -> FsResult<NamedTempFile> {
+        let mut copy = NamedTempFile::new()?;
+        let mut offset = 0;
+        while offset < size {
+            let part_size = COPY_PART_SIZE.min(size - offset);
+            let part = self.connection.read_range(ino, offset, part_size).await?;
+            copy.write_all(&part)?;
+            offset += part.len() as u64;
+            if (part.len() as u64) < part_size {
+                break;
+            }
+        }
+        copy.as_file().set_len(size)?;
+        Ok(copy)
+    }
+
+    async fn set_cached_size(&self, ino: u64, size: u64) {
+        self.update_cached(ino, |file| file.attr.size = size).await;
+    }
+
+    async fn open_file(&self, ino: u64) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::SeqCst);
+        let open_file = OpenFile {
             ino,
-            ..HELLO_DIR_ATTR
+            buffer: Mutex::new(self.unsaved.lock().await.remove(&ino)),
+        };
+        self.open_files.lock().await.insert(fh, Arc::new(open_file));
+        fh
+    }
+
+    async fn get_open_file(&self, fh: u64) -> Option<Arc<OpenFile>> {
+        self.open_files.lock().await.get(&fh).cloned()
+    }
+
+    /// Upload the changes made through the handle, if any. If the upload fails,
+    ///   the changes are kept for the next attempt.
+    async fn flush_file(&self, fh: u64) -> FsResult<()> {
+        if let Some(open_file) = self.get_open_file(fh).await {
+            let mut buffer = open_file.buffer.lock().await;
+            if let Some(data) = &*buffer {
+                self.connection.write_to_file(data, open_file.ino).await?;
+                *buffer = None;
+            }
         }
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub async fn remove_meta(&mut self) {
-        self.connection.cleanup().await;
+    /// Change the size of the file. If the file has local copies, only they are changed.
+    async fn truncate(&self, ino: u64, size: u64) -> FsResult<()> {
+        let open_files: Vec<Arc<OpenFile>> = self
+            .open_files
+            .lock()
+            .await
+            .values()
+            .filter(|x| x.ino == ino)
+            .cloned()
+            .collect();
+
+        let mut buffered = false;
+        for open_file in open_files {
+            if let Some(buffer) = &*open_file.buffer.lock().await {
+                buffer.as_file().set_len(size)?;
+                buffered = true;
+            }
+        }
+        if let Some(buffer) = self.unsaved.lock().await.get(&ino) {
+            buffer.as_file().set_len(size)?;
+            buffered = true;
+        }
+
+        if buffered {
+            self.set_cached_size(ino, size).await;
+            Ok(())
+        } else {
+            let content = self.copy_content(ino, size).await?;
+            self.store_content(ino, &content).await
+        }
     }
 
-    fn next_ino(&mut self) -> u64 {
-        Runtime::new()
-            .unwrap()
-            .block_on(self.connection.get_and_inc_ino())
+    async fn get_ino(&self, ino: u64) -> FsResult<FileLink> {
+        if let Some(data) = self.cache.lock().await.get(ino) {
+            return Ok(data);
+        }
+
+        let link = self.connection.get_file_attr(&ino).await?;
+        self.cache.lock().await.insert(ino, None, link.clone());
+        Ok(link)
     }
 
-    fn get_ino(&mut self, ino: u64) -> Option<FileLink> {
-        let attr = self.get_cur_cache().iter().find(|x| x.attr.ino == ino);
-        if let Some(data) = attr {
-            Some(data.clone())
+    /// Replace the link restored from the previous mount with the actual one.
+    async fn validate(&self, ino: u64) -> FsResult<()> {
+        if !self.cache.lock().await.is_restored(ino) {
+            return Ok(());
+        }
+
+        match self.connection.get_file_attr(&ino).await {
+            Ok(link) => {
+                self.cache.lock().await.insert(ino, None, link);
+                Ok(())
+            }
+            Err(FsError::NotFound) => {
+                self.cache.lock().await.remove(ino);
+                Err(FsError::NotFound)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Remove the child from the storage and from the cache.
+    ///   The inode is kept while it has other names.
+    async fn remove_child(&self, parent: u64, name: &str) -> FsResult<()> {
+        let file_ino = self.find_child(parent, name).await?.attr.ino;
+        let names_left = self.connection.unlink(file_ino, parent, name).await?;
+
+        if names_left == 0 {
+            self.unsaved.lock().await.remove(&file_ino);
+        }
+        let mut cache = self.cache.lock().await;
+        if names_left == 0 {
+            cache.remove(file_ino);
         } else {
-            Runtime::new()
-                .unwrap()
-                .block_on(self.connection.get_file_attr(&ino))
+            cache.remove_child(parent, name);
+            cache.update(file_ino, |x| x.attr.nlink = names_left);
+        }
+        Ok(())
+    }
+
+    /// Add the content written through the handle to its local copy, the copy is created from
+    ///   the stored content on the first write. Returns the new size of the file.
+    async fn write_buffered(&self, fh: u64, ino: u64, offset: u64, data: &[u8]) -> FsResult<u64> {
+        let open_file = match self.get_open_file(fh).await {
+            Some(open_file) => open_file,
+            None => return Err(FsError::Io(io::Error::from_raw_os_error(EBADF))),
+        };
+
+        let mut buffer = open_file.buffer.lock().await;
+        if buffer.is_none() {
+            let size = self.get_ino(ino).await?.attr.size;
+            *buffer = Some(self.copy_content(ino, size).await?);
         }
+
+        let buffer = buffer.as_mut().unwrap();
+        buffer.seek(SeekFrom::Start(offset))?;
+        buffer.write_all(data)?;
+        Ok(buffer.as_file().metadata()?.len())
     }
 }
 
-impl Filesystem for Fpfs {
+impl<B: StorageBackend + 'static> Filesystem for Fpfs<B> {
     fn init(&mut self, _req: &Request) -> Result<(), i32> {
-        self.connection.check_or_init_meta(&HELLO_DIR_ATTR);
-        self.init_cache(&HELLO_DIR_ATTR.ino);
-        Ok(())
+        let state = self.state.clone();
+        let snapshot = self
+            .disk_cache
+            .as_ref()
+            .and_then(|x| x.take_metadata::<CacheSnapshot>());
+        let result = self.runtime.block_on(async move {
+            state.connection.check_or_init_meta(&HELLO_DIR_ATTR).await?;
+            // The cache saved for another filesystem is dropped, e.g. when the cache directory
+            //   is used with another chat
+            match snapshot {
+                Some(data) if data.belongs_to(state.connection.storage_id()) => {
+                    state.cache.lock().await.restore(data)
+                }
+                _ => {
+                    state.children(HELLO_DIR_ATTR.ino).await?;
+                }
+            }
+            Ok(())
+        });
+        result.map_err(|e: FsError| {
+            log::error!("Can't mount the filesystem: {}", e);
+            e.errno()
+        })
     }
 
     fn destroy(&mut self, _req: &Request) {}
 
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let my_file_name = name.to_str().unwrap_or("~").to_string();
-        let found_file = self
-            .get_cache(&parent)
-            .iter()
-            .find(|x| x.name == my_file_name);
-        if found_file.is_some() {
-            reply.entry(&TTL, &found_file.unwrap().attr, 0);
-        } else {
-            reply.error(ENOENT);
-        }
+        let my_file_name = utf8_name!(name, reply);
+        self.spawn(|state| async move {
+            match state.find_child(parent, &my_file_name).await {
+                Ok(data) => {
+                    state.cache.lock().await.lookup(data.attr.ino);
+                    reply.entry(&TTL, &state.shown_attr(&data.attr), 0);
+                }
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
-    fn forget(&mut self, _req: &Request, _ino: u64, _nlookup: u64) {}
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        self.spawn(|state| async move {
+            state.cache.lock().await.forget(ino, nlookup);
+        });
+    }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        let attr = self.get_ino(ino);
-        if let Some(data) = attr {
-            reply.attr(&TTL, &data.attr)
-        } else {
-            reply.error(ENOENT)
-        }
+        self.spawn(|state| async move {
+            match state.get_ino(ino).await {
+                Ok(data) => reply.attr(&TTL, &state.shown_attr(&data.attr)),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
     fn setattr(
@@ -197,28 +503,49 @@ impl Filesystem for Fpfs {
         flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        let attr = self.get_ino(ino);
-        if let Some(data) = attr {
-            let mut attrbts = data.attr;
-            attrbts.uid = uid.unwrap_or(attrbts.uid);
-            attrbts.gid = gid.unwrap_or(attrbts.gid);
-            attrbts.size = size.unwrap_or(attrbts.size);
-            attrbts.atime = atime.unwrap_or(attrbts.atime);
-            attrbts.mtime = mtime.unwrap_or(attrbts.mtime);
-            attrbts.crtime = crtime.unwrap_or(attrbts.crtime);
-            attrbts.flags = flags.unwrap_or(attrbts.flags);
-
-            // FIXME update cache
-            self.connection.set_attr(ino, attrbts.clone());
-
-            reply.attr(&TTL, &attrbts)
-        } else {
-            reply.error(ENOENT)
-        }
+        self.spawn(|state| async move {
+            let result: FsResult<FileAttr> = async {
+                let data = state.get_ino(ino).await?;
+                if let Some(new_size) = size {
+                    if new_size != data.attr.size {
+                        // Truncate or extend the content itself, not only the attribute
+                        state.truncate(ino, new_size).await?;
+                    }
+                }
+
+                let mut attrbts = data.attr;
+                attrbts.uid = uid.unwrap_or(attrbts.uid);
+                attrbts.gid = gid.unwrap_or(attrbts.gid);
+                attrbts.size = size.unwrap_or(attrbts.size);
+                attrbts.atime = atime.unwrap_or(attrbts.atime);
+                attrbts.mtime = mtime.unwrap_or(attrbts.mtime);
+                attrbts.crtime = crtime.unwrap_or(attrbts.crtime);
+                attrbts.flags = flags.unwrap_or(attrbts.flags);
+
+                state.connection.set_attr(ino, attrbts.clone()).await?;
+                state.update_cached(ino, |x| x.attr = attrbts).await;
+                Ok(attrbts)
+            }
+            .await;
+
+            match result {
+                Ok(attr) => reply.attr(&TTL, &state.shown_attr(&attr)),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
-    fn readlink(&mut self, _req: &Request, _ino: u64, reply: ReplyData) {
-        reply.error(ENOSYS);
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        self.spawn(|state| async move {
+            match state.get_ino(ino).await {
+                Ok(FileLink {
+                    target: Some(target),
+                    ..
+                }) => reply.data(target.as_bytes()),
+                Ok(_) => reply.error(EINVAL),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
     fn mknod(
@@ -234,60 +561,94 @@ impl Filesystem for Fpfs {
     }
 
     fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
-        let next_ino = self.next_ino();
-        let dir_name = name.to_str().unwrap().to_string();
-        let attr = Fpfs::make_dir_attr(next_ino);
-        let file_link = FileLink::new_dir(dir_name.clone(), vec![], attr.clone());
-        self.connection
-            .create_dir(dir_name.as_str(), next_ino, Some(parent), &attr);
-
-        match self.files_cache {
-            Some(ref mut f) => f.push(file_link),
-            None => (),
-        }
+        let dir_name = utf8_name!(name, reply);
+        self.spawn(|state| async move {
+            let result: FsResult<FileAttr> = async {
+                let parent_link = state.get_ino(parent).await?;
+                let (connection, name) = (&state.connection, &dir_name);
+                let attr = state
+                    .create_inode(|ino| async move {
+                        let attr = Self::make_dir_attr(ino);
+                        connection
+                            .create_dir(name, ino, Some(parent), &attr)
+                            .await?;
+                        Ok(attr)
+                    })
+                    .await?;
+
+                let mut file_link = FileLink::new(dir_name.clone(), attr.clone(), None);
+                compression::inherit(&parent_link, &mut file_link);
+                let mut cache = state.cache.lock().await;
+                cache.add_child(parent, file_link);
+                cache.lookup(attr.ino);
+                Ok(attr)
+            }
+            .await;
 
-        reply.entry(&TTL, &attr, 0);
+            match result {
+                Ok(attr) => reply.entry(&TTL, &state.shown_attr(&attr), 0),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
     fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let my_file_name = name.to_str().unwrap_or("~").to_string();
-        let cache = self.get_cache_mut(&parent);
-
-        let position = cache.iter().position(|x| x.name == my_file_name);
-        if let Some(idx) = position {
-            let data = cache.remove(idx);
-            let file_ino = data.attr.ino;
-            self.connection.remove_inode(file_ino, parent);
-            reply.ok()
-        } else {
-            reply.error(ENOENT);
-        }
+        let my_file_name = utf8_name!(name, reply);
+        self.spawn(|state| async move {
+            match state.remove_child(parent, &my_file_name).await {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
     fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let my_file_name = name.to_str().unwrap_or("~").to_string();
-        let cache = self.get_cache_mut(&parent);
-
-        let position = cache.iter().position(|x| x.name == my_file_name);
-        if let Some(idx) = position {
-            let data = cache.remove(idx);
-            let file_ino = data.attr.ino;
-            self.connection.remove_inode(file_ino, parent);
-            reply.ok()
-        } else {
-            reply.error(ENOENT);
-        }
+        let my_file_name = utf8_name!(name, reply);
+        self.spawn(|state| async move {
+            match state.remove_child(parent, &my_file_name).await {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
     fn symlink(
         &mut self,
         _req: &Request,
-        _parent: u64,
-        _name: &OsStr,
-        _link: &Path,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
         reply: ReplyEntry,
     ) {
-        reply.error(ENOSYS);
+        let link_name = utf8_name!(name, reply);
+        // The target is stored as a string
+        let target = utf8_name!(link, reply);
+        self.spawn(|state| async move {
+            let result: FsResult<FileAttr> = async {
+                let (connection, name, target) = (&state.connection, &link_name, &target);
+                let attr = state
+                    .create_inode(|ino| async move {
+                        let attr = Self::make_symlink_attr(target.len() as u64, ino);
+                        connection
+                            .create_symlink(name, ino, parent, &attr, target)
+                            .await?;
+                        Ok(attr)
+                    })
+                    .await?;
+
+                let mut cache = state.cache.lock().await;
+                let link = FileLink::new(link_name.clone(), attr, Some(target.clone()));
+                cache.add_child(parent, link);
+                cache.lookup(attr.ino);
+                Ok(attr)
+            }
+            .await;
+
+            match result {
+                Ok(attr) => reply.entry(&TTL, &state.shown_attr(&attr), 0),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
     fn rename(
@@ -299,106 +660,216 @@ impl Filesystem for Fpfs {
         newname: &OsStr,
         reply: ReplyEmpty,
     ) {
-        let my_file_name = name.to_str().unwrap_or("~").to_string();
-        let cache = self.get_cache_mut(&parent);
-
-        let position = cache.iter().position(|x| x.name == my_file_name);
-        if let Some(idx) = position {
-            let data = cache.remove(idx);
-            self.files_cache = None;
-            let file_ino = data.attr.ino;
-            self.connection
-                .rename(file_ino, newname.to_str().unwrap(), parent, newparent);
-            reply.ok()
-        } else {
-            reply.error(ENOENT);
-        }
+        let my_file_name = utf8_name!(name, reply);
+        let new_name = utf8_name!(newname, reply);
+        self.spawn(|state| async move {
+            let result: FsResult<()> = async {
+                let file = state.find_child(parent, &my_file_name).await?.attr;
+                let file_ino = file.ino;
+                let replaced = match state.find_child(newparent, &new_name).await {
+                    Ok(data) => Some(data),
+                    Err(FsError::NotFound) => None,
+                    Err(e) => return Err(e),
+                };
+                // Both names are links of the same inode, nothing changes
+                if replaced.as_ref().map(|x| x.attr.ino) == Some(file_ino) {
+                    return Ok(());
+                }
+                // A directory replaces only a directory, a file replaces only a file
+                if let Some(data) = &replaced {
+                    let error = match (file.kind, data.attr.kind) {
+                        (FileType::Directory, FileType::Directory) => None,
+                        (FileType::Directory, _) => Some(ENOTDIR),
+                        (_, FileType::Directory) => Some(EISDIR),
+                        _ => None,
+                    };
+                    if let Some(error) = error {
+                        return Err(FsError::Io(io::Error::from_raw_os_error(error)));
+                    }
+                }
+
+                state
+                    .connection
+                    .rename(file_ino, &my_file_name, &new_name, parent, newparent)
+                    .await?;
+
+                let mut cache = state.cache.lock().await;
+                if let Some(data) = replaced {
+                    // The replaced inode loses the name, the same as on unlink
+                    let ino = data.attr.ino;
+                    if data.attr.kind == FileType::Directory || data.attr.nlink <= 1 {
+                        cache.remove(ino);
+                    } else {
+                        cache.remove_child(newparent, &new_name);
+                        cache.update(ino, |x| x.attr.nlink -= 1);
+                    }
+                }
+                cache.rename(file_ino, &my_file_name, parent, &new_name, newparent);
+                Ok(())
+            }
+            .await;
+
+            match result {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
     fn link(
         &mut self,
         _req: &Request,
-        _ino: u64,
-        _newparent: u64,
-        _newname: &OsStr,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
         reply: ReplyEntry,
     ) {
-        reply.error(ENOSYS);
+        let new_name = utf8_name!(newname, reply);
+        self.spawn(|state| async move {
+            let result: FsResult<FileAttr> = async {
+                let link = state.connection.link(ino, newparent, &new_name).await?;
+                let attr = link.attr;
+
+                let mut cache = state.cache.lock().await;
+                cache.add_child(newparent, link);
+                cache.lookup(ino);
+                Ok(attr)
+            }
+            .await;
+
+            match result {
+                Ok(attr) => reply.entry(&TTL, &state.shown_attr(&attr), 0),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
-    fn open(&mut self, _req: &Request, _ino: u64, flags: u32, reply: ReplyOpen) {
-        reply.opened(0, flags);
+    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        self.spawn(|state| async move {
+            if let Err(e) = state.validate(ino).await {
+                reply.error(e.errno());
+                return;
+            }
+            let fh = state.open_file(ino).await;
+            reply.opened(fh, flags);
+        });
     }
 
     fn read(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
-        _size: u32,
+        size: u32,
         reply: ReplyData,
     ) {
-        let file_data = Runtime::new()
-            .unwrap()
-            .block_on(self.connection.read_file(ino));
-        match file_data {
-            Some(data) => {
-                let data_array = &data[offset as usize..];
-                reply.data(data_array)
-            }
-            None => reply.error(ENOENT),
-        }
+        self.spawn(|state| async move {
+            // Not uploaded changes should be visible through the same handle
+            if let Some(open_file) = state.get_open_file(fh).await {
+                if let Some(buffer) = &mut *open_file.buffer.lock().await {
+                    let mut data = vec![];
+                    let result = buffer
+                        .seek(SeekFrom::Start(offset as u64))
+                        .and_then(|_| buffer.take(size as u64).read_to_end(&mut data));
+                    match result {
+                        Ok(_) => reply.data(&data),
+                        Err(e) => reply.error(FsError::Io(e).errno()),
+                    }
+                    return;
+                }
+            }
+
+            let file_data = state
+                .connection
+                .read_range(ino, offset as u64, size as u64)
+                .await;
+            match file_data {
+                Ok(data) => reply.data(&data),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
     fn write(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
-        _offset: i64,
+        fh: u64,
+        offset: i64,
         data: &[u8],
         _flags: u32,
         reply: ReplyWrite,
     ) {
-        let path = Fpfs::write_my_file(data);
-
-        self.connection.write_to_file(path, ino);
-
-        self.get_cur_cache_mut()
-            .iter_mut()
-            .find(|x| x.attr.ino == ino)
-            .unwrap()
-            .attr
-            .size = data.len() as u64;
-
-        reply.written(data.len() as u32)
+        let data = data.to_vec();
+        self.spawn(|state| async move {
+            // The changes are collected in a local copy of the file which is uploaded on flush
+            match state.write_buffered(fh, ino, offset as u64, &data).await {
+                Ok(size) => {
+                    state.set_cached_size(ino, size).await;
+                    reply.written(data.len() as u32)
+                }
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
-    fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    fn flush(&mut self, _req: &Request, _ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        self.spawn(|state| async move {
+            match state.flush_file(fh).await {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
     fn release(
         &mut self,
         _req: &Request,
         _ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: u32,
         _lock_owner: u64,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        reply.ok();
+        self.spawn(|state| async move {
+            let result = state.flush_file(fh).await;
+            let open_file = state.open_files.lock().await.remove(&fh);
+            // The changes aren't dropped with the handle, nobody could flush them anymore
+            if let (Err(e), Some(open_file)) = (&result, open_file) {
+                if let Some(buffer) = open_file.buffer.lock().await.take() {
+                    log::error!(
+                        "Can't upload inode {}, the changes are kept until it's opened again: {}",
+                        open_file.ino,
+                        e
+                    );
+                    state.unsaved.lock().await.insert(open_file.ino, buffer);
+                }
+            }
+            match result {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
-    fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    fn fsync(&mut self, _req: &Request, _ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        self.spawn(|state| async move {
+            match state.flush_file(fh).await {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
     fn opendir(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
-        self.init_cache(&ino);
-        reply.opened(0, flags);
+        self.spawn(|state| async move {
+            match state.children(ino).await {
+                Ok(_) => reply.opened(0, flags),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
     fn readdir(
@@ -409,20 +880,29 @@ impl Filesystem for Fpfs {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        let mut entries: Vec<(u64, FileType, String)> = vec![
-            (1, FileType::Directory, String::from(".")),
-            (1, FileType::Directory, String::from("..")),
-        ];
-
-        for file in self.get_cache(&ino) {
-            entries.push((file.attr.ino, FileType::RegularFile, file.name.to_string()))
-        }
+        self.spawn(|state| async move {
+            let mut entries: Vec<(u64, FileType, String)> = vec![
+                (1, FileType::Directory, String::from(".")),
+                (1, FileType::Directory, String::from("..")),
+            ];
+
+            let children = match state.children(ino).await {
+                Ok(data) => data,
+                Err(e) => {
+                    reply.error(e.errno());
+                    return;
+                }
+            };
+            for file in children {
+                entries.push((file.attr.ino, file.attr.kind, file.name))
+            }
 
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            // i + 1 means the index of the next entry
-            reply.add(entry.0, (i + 1) as i64, entry.1, entry.2.as_str());
-        }
-        reply.ok();
+            for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+                // i + 1 means the index of the next entry
+                reply.add(entry.0, (i + 1) as i64, entry.1, entry.2.as_str());
+            }
+            reply.ok();
+        });
     }
 
     fn releasedir(&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, reply: ReplyEmpty) {
@@ -454,21 +934,38 @@ impl Filesystem for Fpfs {
         _position: u32,
         reply: ReplyEmpty,
     ) {
-        let name = name.to_str().unwrap().to_string();
+        let name = utf8_name!(name, reply);
         let vec = value.to_vec();
-        self.connection.set_xattr(ino, name.clone(), vec.clone());
-        self.get_cur_cache_mut().iter_mut().for_each(|x| {
-            if x.attr.ino == ino {
-                x.xattr.insert(name.clone(), vec.clone());
+        self.spawn(|state| async move {
+            let result = state
+                .connection
+                .set_xattr(ino, name.clone(), vec.clone())
+                .await;
+            if let Err(e) = result {
+                reply.error(e.errno());
+                return;
             }
+
+            state
+                .update_cached(ino, |x| {
+                    x.xattr.insert(name, vec);
+                })
+                .await;
+            reply.ok();
         });
-        reply.ok();
     }
 
     fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
-        let file_link = self.get_ino(ino);
-        if let Some(data) = file_link {
-            let attr_name = name.to_str().unwrap().to_string();
+        let attr_name = utf8_name!(name, reply);
+        self.spawn(|state| async move {
+            let data = match state.get_ino(ino).await {
+                Ok(data) => data,
+                Err(e) => {
+                    reply.error(e.errno());
+                    return;
+                }
+            };
+
             let attr_value = data.xattr.get(&attr_name);
             let attr_size = attr_value.map(|x| x.len()).unwrap_or(0) as u32;
             if size == 0 {
@@ -478,15 +975,19 @@ impl Filesystem for Fpfs {
             } else {
                 reply.error(ERANGE)
             }
-        } else {
-            reply.error(ENOSYS);
-        }
+        });
     }
 
     fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
-        let file_link = self.get_ino(ino);
+        self.spawn(|state| async move {
+            let data = match state.get_ino(ino).await {
+                Ok(data) => data,
+                Err(e) => {
+                    reply.error(e.errno());
+                    return;
+                }
+            };
 
-        if let Some(data) = file_link {
             let names: Vec<String> = data.xattr.keys().map(|x| x.to_string()).collect();
             let name_string: String = names.join("\0");
             let attr_size = name_string.len() as u32;
@@ -497,21 +998,24 @@ impl Filesystem for Fpfs {
             } else {
                 reply.error(ERANGE);
             }
-        } else {
-            reply.error(ENOSYS);
-        }
+        });
     }
 
     fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
-        let attr_name = name.to_str().unwrap().to_string();
-        self.connection.remove_xattr(ino, attr_name.clone());
-
-        self.get_cur_cache_mut().iter_mut().for_each(|x| {
-            if x.attr.ino == ino {
-                x.xattr.remove(attr_name.as_str());
+        let attr_name = utf8_name!(name, reply);
+        self.spawn(|state| async move {
+            if let Err(e) = state.connection.remove_xattr(ino, attr_name.clone()).await {
+                reply.error(e.errno());
+                return;
             }
+
+            state
+                .update_cached(ino, |x| {
+                    x.xattr.remove(attr_name.as_str());
+                })
+                .await;
+            reply.ok();
         });
-        reply.ok();
     }
 
     fn access(&mut self, _req: &Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {
@@ -527,19 +1031,36 @@ impl Filesystem for Fpfs {
         flags: u32,
         reply: ReplyCreate,
     ) {
-        let next_ino = self.next_ino();
-        let file_name = name.to_str().unwrap().to_string();
-        let attr = Fpfs::make_attr(0, next_ino);
-        let file_link = FileLink::new_file(file_name.clone(), attr.clone());
-        self.connection
-            .create_file(file_name.as_str(), next_ino, parent, &attr);
-
-        match self.files_cache {
-            Some(ref mut f) => f.push(file_link),
-            None => (),
-        }
-
-        reply.created(&TTL, &attr, 0, 0, flags);
+        let file_name = utf8_name!(name, reply);
+        self.spawn(|state| async move {
+            let result: FsResult<FileAttr> = async {
+                let parent_link = state.get_ino(parent).await?;
+                let (connection, name) = (&state.connection, &file_name);
+                let attr = state
+                    .create_inode(|ino| async move {
+                        let attr = Self::make_attr(0, ino);
+                        connection.create_file(name, ino, parent, &attr).await?;
+                        Ok(attr)
+                    })
+                    .await?;
+
+                let mut file_link = FileLink::new(file_name.clone(), attr.clone(), None);
+                compression::inherit(&parent_link, &mut file_link);
+                let mut cache = state.cache.lock().await;
+                cache.add_child(parent, file_link);
+                cache.lookup(attr.ino);
+                Ok(attr)
+            }
+            .await;
+
+            match result {
+                Ok(attr) => {
+                    let fh = state.open_file(attr.ino).await;
+                    reply.created(&TTL, &state.shown_attr(&attr), 0, fh, flags);
+                }
+                Err(e) => reply.error(e.errno()),
+            }
+        });
     }
 
     fn getlk(
@@ -599,10 +1120,17 @@ impl Filesystem for Fpfs {
     }
 }
 
-impl Fpfs {
-    pub fn write_my_file(data: &[u8]) -> NamedTempFile {
-        let mut temp_file = NamedTempFile::new().unwrap();
-        temp_file.write(data).unwrap();
-        temp_file
+impl<B: StorageBackend> Drop for Fpfs<B> {
+    /// `destroy` isn't called on every unmount, so the cache is saved when the session
+    ///   drops the filesystem.
+    fn drop(&mut self) {
+        if let Some(disk_cache) = &self.disk_cache {
+            if let Ok(cache) = self.state.cache.try_lock() {
+                let snapshot = cache.snapshot(self.state.connection.storage_id());
+                if let Err(e) = disk_cache.save_metadata(&snapshot) {
+                    log::error!("Can't save the cache: {}", e);
+                }
+            }
+        }
     }
 }
@@ -1,10 +1,31 @@
+mod cache;
+mod chat;
+mod cli;
+mod compression;
+mod config;
+mod disk_cache;
+mod encrypted_chat;
+mod error;
 mod external_serialization;
 mod fpfs;
+mod mock;
+mod scheduler;
 mod serialization;
+mod storage;
 mod tg;
+mod tg_chat;
 mod tg_tools;
 mod types;
 mod utils;
 
+pub use chat::Chat;
+pub use cli::{Cli, Command, MountOptions};
+pub use config::Config;
+pub use disk_cache::DiskCache;
+pub use encrypted_chat::EncryptedChat;
+pub use error::{FsError, FsResult};
 pub use fpfs::Fpfs;
+pub use mock::MockChat;
+pub use storage::StorageBackend;
 pub use tg::TgConnection;
+pub use tg_chat::TgChat;
@@ -0,0 +1,121 @@
+use std::io::{self, BufRead, Write};
+
+use grammers_client::{Client, SignInError};
+
+use crate::config::Config;
+use crate::tg::{connect_client, is_signed_in};
+
+/// Sign in to telegram and save the session into the file from the config,
+///   so the filesystem can be mounted.
+///
+/// Users sign in with the phone number and the code sent by telegram, plus the two-step
+///   verification password if it's enabled. Bots sign in with the token from @BotFather.
+pub async fn login(config: &Config, bot_token: Option<&str>) -> Result<(), String> {
+    let mut client = connect_client(config).await?;
+
+    if is_signed_in(&mut client).await? {
+        println!(
+            "Already signed in, remove {} to sign in again",
+            config.session.display()
+        );
+        return Ok(());
+    }
+
+    match bot_token {
+        Some(token) => {
+            client
+                .bot_sign_in(token, config.api_id, &config.api_hash)
+                .await
+                .map_err(|e| format!("Bot sign in failed: {}", e))?;
+        }
+        None => user_sign_in(&mut client, config.api_id, &config.api_hash).await?,
+    }
+
+    client
+        .session()
+        .save()
+        .map_err(|e| format!("Can't save the session: {}", e))?;
+    drop(client);
+
+    // Check the saved session the same way the mount does
+    let mut client = connect_client(config).await?;
+    if !is_signed_in(&mut client).await? {
+        return Err("The saved session is not signed in".to_string());
+    }
+    println!(
+        "Signed in, the session is saved to {}",
+        config.session.display()
+    );
+    Ok(())
+}
+
+/// Wrong codes and passwords are asked again, other failures end the sign in.
+async fn user_sign_in(client: &mut Client, api_id: i32, api_hash: &str) -> Result<(), String> {
+    let phone = prompt("Phone number (international format): ")?;
+    let token = client
+        .request_login_code(&phone, api_id, api_hash)
+        .await
+        .map_err(|e| format!("Can't send the login code: {}", e))?;
+
+    let mut code = prompt("Code sent by telegram: ")?;
+    loop {
+        // After a wrong password the same code gives a new password request
+        match client.sign_in(&token, &code).await {
+            Ok(_) => return Ok(()),
+            Err(SignInError::InvalidCode) => {
+                println!("Invalid code, try again");
+                code = prompt("Code sent by telegram: ")?;
+            }
+            Err(SignInError::PasswordRequired(password_token)) => {
+                let message = match password_token.hint() {
+                    Some(hint) => format!("Two-step verification password (hint: {}): ", hint),
+                    None => "Two-step verification password: ".to_string(),
+                };
+                let password = prompt_password(&message)?;
+                match client
+                    .check_password(password_token, password.as_str())
+                    .await
+                {
+                    Ok(_) => return Ok(()),
+                    Err(SignInError::InvalidPassword) => println!("Wrong password, try again"),
+                    Err(e) => return Err(format!("Sign in failed: {}", e)),
+                }
+            }
+            Err(e) => return Err(format!("Sign in failed: {}", e)),
+        }
+    }
+}
+
+/// The line typed by the user, an error if the input is closed.
+pub fn prompt(message: &str) -> Result<String, String> {
+    print!("{}", message);
+    let _ = io::stdout().flush();
+
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) => Err("The input is closed".to_string()),
+        Ok(_) => Ok(line.trim().to_string()),
+        Err(e) => Err(format!("Can't read the input: {}", e)),
+    }
+}
+
+/// Same as `prompt`, but the typed text isn't shown in the terminal.
+fn prompt_password(message: &str) -> Result<String, String> {
+    let fd = libc::STDIN_FILENO;
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    let is_terminal = unsafe { libc::tcgetattr(fd, &mut termios) } == 0;
+
+    if is_terminal {
+        let mut hidden = termios;
+        hidden.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &hidden) };
+    }
+
+    let password = prompt(message);
+
+    if is_terminal {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+        println!();
+    }
+    password
+}
@@ -1,44 +1,283 @@
-use std::ffi::OsStr;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::process::{self, Command as Process};
 
-use crate::tg::TgConnection;
-use log;
+use crate::cli::{Cli, Command, MountOptions, USAGE};
+use crate::config::Config;
+use crate::disk_cache::DiskCache;
+use crate::encrypted_chat::EncryptedChat;
+use crate::storage::StorageBackend;
+use crate::tg::{connect_client, is_signed_in, TgConnection};
+use crate::tg_chat::TgChat;
 use simple_logger::SimpleLogger;
 use std::env;
+use std::sync::Arc;
 use tokio::runtime::Runtime;
-use tokio::task;
 
+mod cache;
+mod chat;
+mod cli;
+mod compression;
+mod config;
+mod disk_cache;
+mod encrypted_chat;
+mod error;
 mod external_serialization;
 mod fpfs;
+mod login;
+mod scheduler;
 mod serialization;
+mod storage;
 mod tg;
+mod tg_chat;
 mod tg_tools;
 mod types;
 mod utils;
 
+/// Sent by the background process when the filesystem is mounted, see `daemonize`.
+const MOUNTED: &'static str = "mounted";
+
 fn main() {
-    Runtime::new().unwrap().block_on(start());
-}
+    let args: Vec<String> = env::args().collect();
+    let cli = match Cli::parse(&args) {
+        Ok(data) => data,
+        Err(e) => exit_with(&e),
+    };
 
-async fn start() {
     SimpleLogger::new()
-        .with_level(log::LevelFilter::Debug)
+        .with_level(cli.log_level)
         .init()
         .unwrap();
 
-    let args: Vec<String> = env::args().collect();
+    if let Command::Help = cli.command {
+        println!("{}", USAGE);
+        return;
+    }
+    if let Command::Unmount { mountpoint } = &cli.command {
+        unmount(mountpoint);
+        return;
+    }
+
+    let config = match Config::load(&cli.flags) {
+        Ok(data) => data,
+        Err(e) => exit_with(&e),
+    };
+
+    match cli.command {
+        Command::Mount {
+            mountpoint,
+            options,
+        } => mount(&config, &mountpoint, &options),
+        Command::Login { bot_token } => {
+            let runtime = Runtime::new().unwrap();
+            if let Err(e) = runtime.block_on(login::login(&config, bot_token.as_deref())) {
+                exit_with(&e);
+            }
+        }
+        Command::Status => status(&config),
+        Command::Cleanup { confirmed } => cleanup(&config, confirmed),
+        Command::ClearCache => {
+            if config.cache_dir.exists() {
+                fs::remove_dir_all(&config.cache_dir).unwrap();
+            }
+            println!("Removed {}", config.cache_dir.display());
+        }
+        Command::Help | Command::Unmount { .. } => unreachable!(),
+    }
+}
 
-    let mountpoint = args.last().unwrap();
+fn mount(config: &Config, mountpoint: &str, options: &MountOptions) {
+    // The runtime threads don't survive fork, so detach before anything is started
+    let starter = if options.foreground {
+        None
+    } else {
+        Some(daemonize())
+    };
 
-    let (connection, client) = TgConnection::connect().await;
+    let mut session = match start_session(config, mountpoint, options) {
+        Ok(data) => data,
+        Err(e) => {
+            if let Some(mut pipe) = starter {
+                let _ = pipe.write_all(e.as_bytes());
+            }
+            exit_with(&e)
+        }
+    };
+    if let Some(mut pipe) = starter {
+        let _ = pipe.write_all(MOUNTED.as_bytes());
+    }
+
+    if let Err(e) = session.run() {
+        exit_with(&format!("The filesystem stopped: {}", e));
+    }
+}
+
+/// Connect to telegram and mount the filesystem, the requests are handled by `Session::run`.
+fn start_session(
+    config: &Config,
+    mountpoint: &str,
+    options: &MountOptions,
+) -> Result<fuse::Session<fpfs::Fpfs<TgConnection<EncryptedChat<TgChat>>>>, String> {
+    // The telegram client and all the filesystem requests share this runtime
+    let runtime = Arc::new(Runtime::new().unwrap());
 
-    let options = ["-f", "-o", "fsname=fpfs"]
+    let disk_cache = match DiskCache::open(&config.cache_dir, config.cache_size) {
+        Ok(data) => Arc::new(data),
+        Err(e) => return Err(format!("Can't open the cache directory: {}", e)),
+    };
+    let connect = TgConnection::connect(config, Some(disk_cache.clone()));
+    let connection = runtime
+        .block_on(connect)?
+        .with_compression(options.compress);
+    // A filesystem of another format fails the mount here, not every request with EIO
+    if let Err(e) = runtime.block_on(connection.check_version()) {
+        return Err(format!("Can't mount the filesystem: {}", e));
+    }
+
+    let mut fuse_options = vec!["fsname=fpfs", "subtype=fpfs"];
+    if options.read_only {
+        fuse_options.push("ro");
+    }
+    if options.allow_other {
+        fuse_options.push("allow_other");
+    }
+    let fuse_options = fuse_options
         .iter()
-        .map(|o| o.as_ref())
+        .flat_map(|o| vec![OsStr::new("-o"), OsStr::new(*o)])
         .collect::<Vec<&OsStr>>();
 
-    task::spawn(async move { client.run_until_disconnected().await });
+    let filesystem = fpfs::Fpfs::with_runtime(connection, runtime)
+        .with_disk_cache(disk_cache)
+        .with_owner(options.uid, options.gid);
+    fuse::Session::new(filesystem, Path::new(mountpoint), &fuse_options)
+        .map_err(|e| format!("Can't mount {}: {}", mountpoint, e))
+}
+
+/// Run the rest of the program in the background, detached from the terminal.
+///
+/// The started process waits until the background one writes the result of the mount
+///   into the returned pipe: `MOUNTED` or the error, which is printed then. So the failures
+///   are seen by the user, even though the background process has no terminal.
+fn daemonize() -> File {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        exit_with("Can't start in the background");
+    }
+    let (mut read_end, write_end) =
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => exit_with("Can't start in the background"),
+        0 => drop(read_end),
+        _ => {
+            drop(write_end);
+            // The pipe is closed without a result if the background process dies
+            let mut result = String::new();
+            let _ = read_end.read_to_string(&mut result);
+            match result.as_str() {
+                MOUNTED => process::exit(0),
+                "" => exit_with("The background process exited before mounting"),
+                message => exit_with(message),
+            }
+        }
+    }
 
     unsafe {
-        fuse::spawn_mount(fpfs::Fpfs::new(connection), &mountpoint, &options).unwrap();
+        libc::setsid();
+        let null = CString::new("/dev/null").unwrap();
+        let fd = libc::open(null.as_ptr(), libc::O_RDWR);
+        if fd >= 0 {
+            libc::dup2(fd, libc::STDIN_FILENO);
+            libc::dup2(fd, libc::STDOUT_FILENO);
+            libc::dup2(fd, libc::STDERR_FILENO);
+        }
+    }
+    write_end
+}
+
+fn unmount(mountpoint: &str) {
+    let result = if cfg!(target_os = "macos") {
+        Process::new("umount").arg(mountpoint).status()
+    } else {
+        Process::new("fusermount")
+            .arg("-u")
+            .arg(mountpoint)
+            .status()
+    };
+
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(_) => exit_with(&format!("Can't unmount {}", mountpoint)),
+        Err(e) => exit_with(&format!("Can't unmount {}: {}", mountpoint, e)),
+    }
+}
+
+fn status(config: &Config) {
+    let runtime = Runtime::new().unwrap();
+    runtime.block_on(async {
+        println!("Session: {}", config.session.display());
+        let signed_in = match connect_client(config).await {
+            Ok(mut client) => is_signed_in(&mut client).await,
+            Err(e) => Err(e),
+        };
+        match signed_in {
+            Ok(true) => {}
+            Ok(false) => {
+                println!("Not signed in, run `fpfs login`");
+                return;
+            }
+            Err(e) => exit_with(&e),
+        }
+
+        println!("Chat: {}", config.chat);
+        let connection = match TgConnection::connect(config, None).await {
+            Ok(data) => data,
+            Err(e) => exit_with(&e),
+        };
+
+        match connection.status().await {
+            Ok(Some(data)) => println!(
+                "Filesystem: version {}, {} inodes and {} chunks in {} shards",
+                data.version, data.inodes, data.chunks, data.shards
+            ),
+            Ok(None) => println!("Filesystem: not created yet, it's created on the first mount"),
+            Err(e) => exit_with(&format!("Can't read the filesystem: {}", e)),
+        }
+    });
+}
+
+/// Remove everything the filesystem stored in the chat.
+fn cleanup(config: &Config, confirmed: bool) {
+    if !confirmed {
+        let answer = login::prompt(&format!(
+            "All the files stored in {} will be removed. Continue? [y/N] ",
+            config.chat
+        ))
+        .unwrap_or_default();
+        if answer != "y" && answer != "Y" {
+            return;
+        }
     }
+
+    let runtime = Runtime::new().unwrap();
+    let result = runtime.block_on(async {
+        let connection = match TgConnection::connect(config, None).await {
+            Ok(data) => data,
+            Err(e) => exit_with(&e),
+        };
+
+        connection.cleanup().await
+    });
+    if let Err(e) = result {
+        exit_with(&format!("Can't remove the filesystem: {}", e));
+    }
+    println!("Removed the filesystem from {}", config.chat);
+}
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
 }
@@ -0,0 +1,332 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::chat::{Chat, ChatMessage, EditError};
+use crate::error::{FsError, FsResult};
+use crate::serialization::{from_str, to_string};
+use crate::types::FpfsInputFile;
+
+/// Size of one uploaded part, the same as telegram uses.
+const PART_SIZE: usize = 512 * 1024;
+
+/// Files larger than this are uploaded as big files in telegram.
+const BIG_FILE_SIZE: usize = 10 * 1024 * 1024;
+
+/// Telegram doesn't allow longer messages.
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+const STATE_FILE: &'static str = "messages.json";
+const UPLOADS_DIR: &'static str = "uploads";
+
+/// `Chat` that emulates telegram without any network.
+///
+/// Messages get increasing ids, may have an uploaded file attached and can be edited or
+///   deleted. As in telegram, the text of a message is limited to 4096 characters.
+///   Use `with_edit_window` to simulate `MESSAGE_EDIT_TIME_EXPIRED` for old messages.
+///
+/// The chat created with `in_dir` keeps its messages and uploads in the given directory,
+///   so the same filesystem may be mounted again.
+pub struct MockChat {
+    /// Requests may come from several tasks at once.
+    store: Mutex<MockStore>,
+    edit_window: Option<Duration>,
+    directory: Option<PathBuf>,
+    requests: Arc<AtomicUsize>,
+    downloaded: Arc<AtomicUsize>,
+    searches: Arc<AtomicUsize>,
+    failing_uploads: Arc<AtomicUsize>,
+}
+
+struct MockStore {
+    messages: BTreeMap<i32, MockMessage>,
+    uploads: HashMap<i64, Vec<u8>>,
+    next_id: i32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct MockMessage {
+    id: i32,
+    text: String,
+    file: Option<FpfsInputFile>,
+    /// Seconds since the unix epoch.
+    date: u64,
+    #[serde(default)]
+    pinned: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MockState {
+    messages: Vec<MockMessage>,
+    next_id: i32,
+}
+
+impl MockChat {
+    pub fn in_memory() -> MockChat {
+        MockChat {
+            store: Mutex::new(MockStore {
+                messages: BTreeMap::new(),
+                uploads: HashMap::new(),
+                next_id: 1,
+            }),
+            edit_window: None,
+            directory: None,
+            requests: Arc::new(AtomicUsize::new(0)),
+            downloaded: Arc::new(AtomicUsize::new(0)),
+            searches: Arc::new(AtomicUsize::new(0)),
+            failing_uploads: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Load the chat from the directory, or start an empty one if there is nothing there yet.
+    pub fn in_dir<P: AsRef<Path>>(directory: P) -> MockChat {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(directory.join(UPLOADS_DIR)).unwrap();
+
+        let mut chat = MockChat::in_memory();
+        let store = chat.store.get_mut().unwrap();
+
+        if let Ok(text) = fs::read_to_string(directory.join(STATE_FILE)) {
+            let state: MockState = from_str(&text).unwrap();
+            store.next_id = state.next_id;
+            for message in state.messages {
+                store.messages.insert(message.id, message);
+            }
+        }
+
+        for entry in fs::read_dir(directory.join(UPLOADS_DIR)).unwrap() {
+            let path = entry.unwrap().path();
+            let id: i64 = path.file_name().unwrap().to_str().unwrap().parse().unwrap();
+            store.uploads.insert(id, fs::read(&path).unwrap());
+        }
+
+        chat.directory = Some(directory);
+        chat
+    }
+
+    /// Messages older than `window` can't be edited anymore, as in telegram.
+    pub fn with_edit_window(mut self, window: Duration) -> MockChat {
+        self.edit_window = Some(window);
+        self
+    }
+
+    /// Counter of the requests made to the chat, it's shared with the returned value.
+    pub fn request_counter(&self) -> Arc<AtomicUsize> {
+        self.requests.clone()
+    }
+
+    /// Counter of the downloaded bytes, it's shared with the returned value.
+    pub fn download_counter(&self) -> Arc<AtomicUsize> {
+        self.downloaded.clone()
+    }
+
+    /// Counter of the searches through the history, the real chat pages through all the messages
+    ///   for them. It's shared with the returned value.
+    pub fn search_counter(&self) -> Arc<AtomicUsize> {
+        self.searches.clone()
+    }
+
+    /// Number of the next uploads that fail with a network error, it's shared with the returned
+    ///   value, so the failures may be set while the chat is used.
+    pub fn failing_uploads(&self) -> Arc<AtomicUsize> {
+        self.failing_uploads.clone()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// Every request to the chat takes the store, so the requests are counted here.
+    fn store(&self) -> MutexGuard<MockStore> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        self.store.lock().unwrap()
+    }
+
+    fn save(&self, store: &MockStore) {
+        if let Some(directory) = &self.directory {
+            let state = MockState {
+                messages: store.messages.values().cloned().collect(),
+                next_id: store.next_id,
+            };
+            fs::write(directory.join(STATE_FILE), to_string(&state).unwrap()).unwrap();
+        }
+    }
+
+    fn attached_file(store: &MockStore, id: i32) -> Option<&Vec<u8>> {
+        let file = store.messages.get(&id)?.file.as_ref()?;
+        store.uploads.get(&file.id)
+    }
+
+    fn to_chat_message(message: &MockMessage) -> ChatMessage {
+        ChatMessage {
+            id: message.id,
+            text: message.text.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Chat for MockChat {
+    async fn send_message(&self, text: String, file: Option<FpfsInputFile>) -> FsResult<i32> {
+        // The real chat fails in the same way
+        if text.chars().count() > MAX_MESSAGE_LENGTH {
+            return Err(FsError::from_rpc("MESSAGE_TOO_LONG", None));
+        }
+
+        let mut store = self.store();
+        let id = store.next_id;
+        store.next_id += 1;
+        store.messages.insert(
+            id,
+            MockMessage {
+                id,
+                text,
+                file,
+                date: MockChat::now(),
+                pinned: false,
+            },
+        );
+        self.save(&store);
+        Ok(id)
+    }
+
+    async fn edit_message(
+        &self,
+        id: i32,
+        text: String,
+        file: Option<FpfsInputFile>,
+    ) -> Result<(), EditError> {
+        if text.chars().count() > MAX_MESSAGE_LENGTH {
+            return Err(FsError::from_rpc("MESSAGE_TOO_LONG", None).into());
+        }
+
+        let mut store = self.store();
+        let message = match store.messages.get_mut(&id) {
+            Some(data) => data,
+            None => return Err(FsError::from_rpc("MESSAGE_ID_INVALID", None).into()),
+        };
+
+        if let Some(window) = self.edit_window {
+            if MockChat::now().saturating_sub(message.date) >= window.as_secs() {
+                return Err(EditError::TimeExpired);
+            }
+        }
+
+        // As in telegram, the attached file is kept unless a new one is given
+        message.text = text;
+        if file.is_some() {
+            message.file = file;
+        }
+        self.save(&store);
+        Ok(())
+    }
+
+    async fn delete_messages(&self, ids: &[i32]) -> FsResult<()> {
+        let mut store = self.store();
+        for id in ids {
+            store.messages.remove(id);
+        }
+        self.save(&store);
+        Ok(())
+    }
+
+    async fn get_messages(&self, ids: &[i32]) -> FsResult<Vec<Option<ChatMessage>>> {
+        let store = self.store();
+        let messages = ids
+            .iter()
+            .map(|id| store.messages.get(id).map(MockChat::to_chat_message))
+            .collect();
+        Ok(messages)
+    }
+
+    async fn find_message(
+        &self,
+        filter: &(dyn Fn(&str) -> bool + Sync),
+    ) -> FsResult<Option<ChatMessage>> {
+        self.searches.fetch_add(1, Ordering::SeqCst);
+        let message = self
+            .store()
+            .messages
+            .values()
+            .rev()
+            .find(|x| filter(&x.text))
+            .map(MockChat::to_chat_message);
+        Ok(message)
+    }
+
+    async fn pin_message(&self, id: i32) -> FsResult<()> {
+        let mut store = self.store();
+        match store.messages.get_mut(&id) {
+            Some(message) => message.pinned = true,
+            None => return Err(FsError::from_rpc("MESSAGE_ID_INVALID", None)),
+        }
+        self.save(&store);
+        Ok(())
+    }
+
+    async fn find_pinned_message(
+        &self,
+        filter: &(dyn Fn(&str) -> bool + Sync),
+    ) -> FsResult<Option<ChatMessage>> {
+        let message = self
+            .store()
+            .messages
+            .values()
+            .rev()
+            .find(|x| x.pinned && filter(&x.text))
+            .map(MockChat::to_chat_message);
+        Ok(message)
+    }
+
+    async fn upload_file(&self, path: &Path) -> FsResult<FpfsInputFile> {
+        let failing = self
+            .failing_uploads
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| x.checked_sub(1));
+        if failing.is_ok() {
+            return Err(FsError::Network("The upload failed".to_string()));
+        }
+
+        let data = fs::read(path)?;
+        let id: i64 = rand::random();
+
+        if let Some(directory) = &self.directory {
+            fs::write(directory.join(UPLOADS_DIR).join(id.to_string()), &data)?;
+        }
+
+        let input_file = FpfsInputFile {
+            id,
+            parts: ((data.len() + PART_SIZE - 1) / PART_SIZE) as i32,
+            name: path.file_name().unwrap().to_str().unwrap().to_string(),
+            md5_checksum: String::new(),
+            big: data.len() > BIG_FILE_SIZE,
+        };
+        self.store().uploads.insert(id, data);
+        Ok(input_file)
+    }
+
+    async fn download_media(&self, id: i32) -> FsResult<Vec<u8>> {
+        let store = self.store();
+        let data = MockChat::attached_file(&store, id).ok_or(FsError::NotFound)?;
+        self.downloaded.fetch_add(data.len(), Ordering::SeqCst);
+        Ok(data.clone())
+    }
+
+    async fn download_range(&self, id: i32, offset: u64, size: u64) -> FsResult<Vec<u8>> {
+        let store = self.store();
+        let data = MockChat::attached_file(&store, id).ok_or(FsError::NotFound)?;
+
+        let start = (offset as usize).min(data.len());
+        let end = (offset + size).min(data.len() as u64) as usize;
+        self.downloaded.fetch_add(end - start, Ordering::SeqCst);
+        Ok(data[start..end].to_vec())
+    }
+}
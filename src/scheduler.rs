@@ -0,0 +1,225 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use grammers_mtproto::mtp::RpcError;
+use grammers_mtsender::InvocationError;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+/// The request budget is counted over this period.
+const BUDGET_WINDOW: Duration = Duration::from_secs(60);
+
+/// Failed requests are repeated at most this amount of times, flood waits are always waited.
+const MAX_RETRIES: u32 = 5;
+
+/// The first repetition of a failed request waits this long, every next one waits twice longer.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longer flood waits fail the request instead of blocking it for that long.
+const MAX_FLOOD_WAIT: u64 = 5 * 60;
+
+/// Requests to telegram go through the scheduler, so they don't hit the limits of telegram.
+///
+/// At most `budget` requests are sent in a minute, the rest wait for their turn. If telegram
+///   still answers with `FLOOD_WAIT_X`, all the requests are paused for X seconds and the failed
+///   one is sent again. Network errors and internal errors of telegram are retried with a backoff.
+pub struct RequestScheduler {
+    /// Zero means no limit.
+    budget: usize,
+    /// `BUDGET_WINDOW` and `FIRST_BACKOFF`, the tests make them shorter.
+    window: Duration,
+    first_backoff: Duration,
+    state: Mutex<SchedulerState>,
+}
+
+struct SchedulerState {
+    /// No requests are sent until this moment.
+    paused_until: Option<Instant>,
+    /// Times of the requests sent during the last `BUDGET_WINDOW`.
+    sent: VecDeque<Instant>,
+}
+
+impl RequestScheduler {
+    /// `budget` is the amount of requests allowed per minute, zero means no limit.
+    pub fn new(budget: usize) -> RequestScheduler {
+        RequestScheduler {
+            budget,
+            window: BUDGET_WINDOW,
+            first_backoff: FIRST_BACKOFF,
+            state: Mutex::new(SchedulerState {
+                paused_until: None,
+                sent: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Send the request, repeating it while the error allows.
+    ///   `request` is called for every attempt.
+    pub async fn run<T, F, Fut>(&self, mut request: F) -> Result<T, InvocationError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, InvocationError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            self.acquire().await;
+            match request().await {
+                Ok(data) => return Ok(data),
+                Err(e) => {
+                    attempt += 1;
+                    if !self.should_retry(&e, attempt).await {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wait until the next request may be sent. Requests that aren't sent by `run`,
+    ///   e.g. the parts of a download, should call it before every request.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().await;
+                let now = Instant::now();
+
+                let paused_until = state.paused_until;
+                match paused_until {
+                    Some(until) if until > now => until - now,
+                    _ => {
+                        state.paused_until = None;
+                        while state
+                            .sent
+                            .front()
+                            .map_or(false, |x| *x + self.window <= now)
+                        {
+                            state.sent.pop_front();
+                        }
+
+                        if self.budget == 0 || state.sent.len() < self.budget {
+                            state.sent.push_back(now);
+                            return;
+                        }
+                        *state.sent.front().unwrap() + self.window - now
+                    }
+                }
+            };
+
+            sleep(wait).await;
+        }
+    }
+
+    /// Decide whether the failed request should be sent again, `attempt` starts from 1.
+    ///   If so, waits until it may be sent.
+    pub async fn should_retry(&self, error: &InvocationError, attempt: u32) -> bool {
+        match error {
+            InvocationError::Rpc(RpcError { name, value, .. }) if name == "FLOOD_WAIT" => {
+                let seconds = value.unwrap_or(1) as u64;
+                if seconds > MAX_FLOOD_WAIT {
+                    return false;
+                }
+
+                log::warn!("Flood wait for {} seconds", seconds);
+                let until = Instant::now() + Duration::from_secs(seconds);
+                let mut state = self.state.lock().await;
+                if state.paused_until.map_or(true, |x| x < until) {
+                    state.paused_until = Some(until);
+                }
+                true
+            }
+            // Only the internal errors of telegram (5xx) may pass when the request is repeated
+            InvocationError::Rpc(RpcError { code, .. }) if *code < 500 => false,
+            _ if attempt > MAX_RETRIES => false,
+            _ => {
+                log::warn!("Request failed, retrying: {}", error);
+                sleep(self.first_backoff * 2u32.pow(attempt - 1)).await;
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(budget: usize) -> RequestScheduler {
+        RequestScheduler {
+            window: Duration::from_millis(300),
+            first_backoff: Duration::from_millis(10),
+            ..RequestScheduler::new(budget)
+        }
+    }
+
+    fn flood_wait(seconds: u32) -> InvocationError {
+        InvocationError::Rpc(RpcError {
+            code: 420,
+            name: "FLOOD_WAIT".to_string(),
+            value: Some(seconds),
+        })
+    }
+
+    #[tokio::test]
+    async fn budget_is_respected() {
+        let scheduler = scheduler(3);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            scheduler.acquire().await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // The fourth request waits until the first one leaves the window
+        scheduler.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn flood_wait_delays_next_request() {
+        let scheduler = scheduler(0);
+        let start = Instant::now();
+
+        // The failed request isn't delayed by itself, the pause applies to every next request
+        assert!(scheduler.should_retry(&flood_wait(1), 1).await);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        scheduler.acquire().await;
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        // Too long waits fail the request
+        let long_wait = flood_wait(MAX_FLOOD_WAIT as u32 + 1);
+        assert!(!scheduler.should_retry(&long_wait, 1).await);
+    }
+
+    #[tokio::test]
+    async fn backoff_is_bounded() {
+        let scheduler = scheduler(0);
+        let start = Instant::now();
+
+        let mut attempts = 0;
+        let result: Result<(), InvocationError> = scheduler
+            .run(|| {
+                attempts += 1;
+                async { Err(InvocationError::Dropped) }
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(MAX_RETRIES + 1, attempts);
+        // 10, 20, 40, 80 and 160 milliseconds between the attempts
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(310));
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let scheduler = scheduler(0);
+        let error = InvocationError::Rpc(RpcError {
+            code: 400,
+            name: "MESSAGE_ID_INVALID".to_string(),
+            value: None,
+        });
+        assert!(!scheduler.should_retry(&error, 1).await);
+    }
+}
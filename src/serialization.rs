@@ -1,6 +1,8 @@
 use serde::{de, Serialize};
 use serde_json::Error;
 
+use crate::utils;
+
 pub fn to_string<T>(obj: &T) -> Result<String, Error>
 where
     T: ?Sized + Serialize,
@@ -14,3 +16,19 @@ where
 {
     serde_json::from_str(s)
 }
+
+/// Serialize the object into a message text starting with the prefix, e.g. `[META]`.
+pub fn to_prefixed_string<T>(prefix: &str, obj: &T) -> Result<String, Error>
+where
+    T: ?Sized + Serialize,
+{
+    let info = to_string(obj)?;
+    Ok(format!("{}\n{}", prefix, info))
+}
+
+pub fn from_prefixed_str<'a, T>(prefix: &str, s: &'a str) -> Result<T, Error>
+where
+    T: de::Deserialize<'a>,
+{
+    from_str(utils::crop_letters(s, prefix.len()))
+}
@@ -0,0 +1,102 @@
+use async_trait::async_trait;
+use fuse::FileAttr;
+use tempfile::NamedTempFile;
+
+use crate::error::FsResult;
+use crate::types::FileLink;
+
+/// Everything `Fpfs` needs from the place where the filesystem is actually stored.
+///
+/// `TgConnection` keeps the data in a telegram chat, but the filesystem logic doesn't care
+///   about it, so any other store may be mounted by implementing this trait.
+///
+/// Failures are returned as `FsError`, they fail the request with the matching errno.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Make sure the storage is initialized and contains the root directory.
+    async fn check_or_init_meta(&self, root_attr: &FileAttr) -> FsResult<()>;
+
+    /// Id of the stored filesystem, known after `check_or_init_meta`.
+    ///   The cache saved by a mount is used only by the mounts of the same filesystem.
+    fn storage_id(&self) -> u64;
+
+    async fn create_file(&self, name: &str, ino: u64, parent: u64, attr: &FileAttr)
+        -> FsResult<()>;
+
+    /// The symlink keeps `target` as is, it's not resolved by the storage.
+    async fn create_symlink(
+        &self,
+        name: &str,
+        ino: u64,
+        parent: u64,
+        attr: &FileAttr,
+        target: &str,
+    ) -> FsResult<()>;
+
+    /// `parent` is `None` only for the root directory.
+    async fn create_dir(
+        &self,
+        name: &str,
+        ino: u64,
+        parent: Option<u64>,
+        attr: &FileAttr,
+    ) -> FsResult<()>;
+
+    /// Read `size` bytes starting from `offset`, less if the file ends earlier.
+    async fn read_range(&self, ino: u64, offset: u64, size: u64) -> FsResult<Vec<u8>>;
+
+    /// Replace the content of the file with the content of `tempfile`.
+    async fn write_to_file(&self, tempfile: &NamedTempFile, ino: u64) -> FsResult<()>;
+
+    /// Children of the directory from the page with the given number,
+    ///   `None` if the directory has less pages.
+    async fn get_directory_page(
+        &self,
+        parent: &u64,
+        page: usize,
+    ) -> FsResult<Option<Vec<FileLink>>>;
+
+    async fn get_directory_files(&self, parent: &u64) -> FsResult<Vec<FileLink>> {
+        let mut files = vec![];
+        let mut page = 0;
+        while let Some(data) = self.get_directory_page(parent, page).await? {
+            files.extend(data);
+            page += 1;
+        }
+        Ok(files)
+    }
+
+    /// `FsError::NotFound` if there is no such inode.
+    async fn get_file_attr(&self, ino: &u64) -> FsResult<FileLink>;
+
+    /// An inode listed under `new_name` in `new_parent` loses that name, as with `unlink`.
+    async fn rename(
+        &self,
+        ino: u64,
+        name: &str,
+        new_name: &str,
+        parent: u64,
+        new_parent: u64,
+    ) -> FsResult<()>;
+
+    /// Add one more name of the inode, returns the updated link.
+    async fn link(&self, ino: u64, new_parent: u64, new_name: &str) -> FsResult<FileLink>;
+
+    /// Remove the name of the inode from the directory. The inode and its content are removed
+    ///   with the last name, directories have only one. Returns the amount of the names left.
+    ///
+    /// `FsError::NotEmpty` if the inode is a directory with children.
+    async fn unlink(&self, ino: u64, parent: u64, name: &str) -> FsResult<u32>;
+
+    async fn set_attr(&self, ino: u64, attr: FileAttr) -> FsResult<()>;
+
+    async fn set_xattr(&self, ino: u64, name: String, data: Vec<u8>) -> FsResult<()>;
+
+    async fn remove_xattr(&self, ino: u64, name: String) -> FsResult<()>;
+
+    /// Return the next free inode and reserve it.
+    async fn get_and_inc_ino(&self) -> FsResult<u64>;
+
+    /// Remove everything stored by this backend.
+    async fn cleanup(&self) -> FsResult<()>;
+}
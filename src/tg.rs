@@ -1,453 +1,1587 @@
 use std::collections::HashMap;
 use std::fs::File;
-
-use fuse::FileAttr;
-use grammers_client::ext::MessageMediaExt;
-use grammers_client::{Client, ClientHandle, Config, InputMessage};
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use fuse::{FileAttr, FileType};
+use grammers_client::{Client, Config as ClientConfig};
 use grammers_session::Session;
-use grammers_tl_types as tl;
 use tempfile::NamedTempFile;
-
-use crate::serialization::{from_str, to_string};
-use crate::tg_tools::{edit_or_recreate, get_message, last_message, resend_message};
-use crate::types::{FileLink, MetaMessage, VERSION};
-use crate::utils;
+use tokio::sync::Mutex;
+
+use crate::chat::{Chat, ChatMessage};
+use crate::compression::{self, FRAME_SIZE};
+use crate::config::Config;
+use crate::disk_cache::DiskCache;
+use crate::encrypted_chat::EncryptedChat;
+use crate::error::{FsError, FsResult};
+use crate::serialization::{from_prefixed_str, from_str, to_prefixed_string, to_string};
+use crate::storage::StorageBackend;
+use crate::tg_chat::{resolve_peer, TgChat};
+use crate::tg_tools::{edit_or_recreate, get_message};
+use crate::types::{
+    ChunkPage, DirEntry, DirPage, FileChunk, FileLink, MetaIndex, MetaMessage, MetaShard,
+    MetaVersion, VERSION,
+};
 
 const META_CONSTANT: &'static str = "[META]";
+const INDEX_CONSTANT: &'static str = "[INDEX]";
+const SHARD_CONSTANT: &'static str = "[SHARD]";
+const DIR_CONSTANT: &'static str = "[DIR]";
+const CHUNK_CONSTANT: &'static str = "[CHUNK]";
+const CHUNKS_CONSTANT: &'static str = "[CHUNKS]";
+
+/// Amount of inodes in one shard of the inode table, so the shard fits into one message.
+const SHARD_SIZE: u64 = 100;
+
+/// Amount of shards listed in one `[INDEX]` message. The meta message lists a few hundred
+///   index messages, so the table holds millions of inodes.
+const INDEX_SIZE: usize = 300;
+
+/// A directory page is filled until its stored text, e.g. encrypted, reaches this length,
+///   a bit less than telegram allows.
+const MAX_PAGE_LENGTH: usize = 4000;
+
+/// The chunk list is kept in the link while its text is shorter than this,
+///   longer lists are moved to `[CHUNKS]` pages, so the link always fits into a message.
+const MAX_INLINE_CHUNKS_LENGTH: usize = 1000;
+
+/// Telegram doesn't allow files larger than 2GB, so the content is split into chunks.
+const DEFAULT_CHUNK_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Chunks up to this size are downloaded completely on the first read, so they get into the
+///   disk cache. Larger chunks are downloaded by the requested ranges.
+const MAX_CACHED_DOWNLOAD: u64 = 64 * 1024 * 1024;
+
+/// A change of a message is applied again at most this amount of times
+///   when other mounts keep changing the same message.
+const MAX_CONFLICT_RETRIES: u32 = 5;
+
+/// The meta message is read again after this delay when inodes are leased while another mount
+///   changes it, so an edit of the other mount that was sent before ours but landed after
+///   our read back is noticed.
+const LEASE_CHECK_DELAY: Duration = Duration::from_millis(500);
+
+/// Filesystem stored as messages of a chat.
+///
+/// The inode table maps inodes to ids of the messages with the serialized `FileLink`.
+///   It's split into `[SHARD]` messages of `SHARD_SIZE` inodes each. `[INDEX]` messages keep
+///   the ids of `INDEX_SIZE` shards each, and the meta message (prefixed with `[META]`) keeps
+///   the ids of the index messages. The meta message of another `VERSION` isn't read.
+///
+/// Children of the directory are listed in `[DIR]` pages, the directory link keeps their ids.
+///   The pages keep the names of the children, so a file may have several names (hard links),
+///   its link counts them in `nlink`.
+///
+/// Content of the file is split into chunks, each chunk is attached to a separate `[CHUNK]` message.
+///   The link lists the chunks of small files, the longer lists are split into `[CHUNKS]` pages.
+///   Chunks with the same content are uploaded once: the hashes of the known chunks are kept in
+///   `content_index`, by the id of the filesystem. Chunks are numbered by the inode allocator and recorded in the inode table
+///   with the count of the files pointing to them, so the count survives the edit time limit
+///   like any other shard.
+///
+/// The id of the meta message is remembered, and kept in the disk cache between mounts,
+///   so the chat is searched for it only if the id is unknown or outdated.
+///
+/// Several mounts may use the same chat, this is best-effort. The meta message, the shards and
+///   the directory pages are read back after every edit, and the change is applied again if
+///   another mount overwrote it, see `edit_checked`. Telegram has no conditional edits, so
+///   an edit of another mount based on an older text may still land after our read back and
+///   drop our change. Inodes are leased by whole shards (see `lease_inodes`), so the mounts
+///   rarely edit the same shard, and a duplicate inode fails the creation with
+///   `FsError::Conflict` instead of replacing the other file.
+///
+/// Requests may run in parallel. Changes of the meta messages, shards, directory pages and links
+///   are done under `meta_lock`, so they don't overwrite each other.
+pub struct TgConnection<C: Chat> {
+    chat: C,
+    chunk_size: u64,
+    /// Compress the content of the files that don't set `COMPRESSION_XATTR`.
+    compression: bool,
+    /// Chunks by the hash of their content. The chunks uploaded or read by this device get here,
+    ///   the index is kept in the disk cache between mounts.
+    content_index: std::sync::Mutex<HashMap<String, FileChunk>>,
+    meta_lock: Mutex<()>,
+    /// Inodes this mount may allocate without changing the meta message, see `lease_inodes`.
+    lease: std::sync::Mutex<Range<u64>>,
+    /// Id of the meta message, zero if it's not known yet.
+    meta_id: AtomicI32,
+    /// Stamp of the meta message as this mount saw it last, another stamp before our edit means
+    ///   that another mount changes the meta message too.
+    meta_stamp: AtomicU64,
+    /// Id of the filesystem from the meta message, zero until `check_or_init_meta`.
+    storage_id: AtomicU64,
+    disk_cache: Option<Arc<DiskCache>>,
+}
 
-pub struct TgConnection {
-    client_handler: ClientHandle,
+/// Summary of the stored filesystem, shown by `fpfs status`.
+pub struct StorageStatus {
+    pub version: String,
+    pub shards: usize,
+    /// Amount of the inodes in the inode table.
+    pub inodes: usize,
+    /// Amount of the stored chunks, the shared ones are counted once.
+    pub chunks: usize,
 }
 
-impl TgConnection {
-    pub async fn connect() -> (TgConnection, Client) {
-        let api_id: i32 = env!("TG_ID").parse().expect("TG_ID invalid");
-        let api_hash = env!("TG_HASH").to_string();
+/// Connect to telegram with the session from the config, the session may be not signed in yet.
+pub async fn connect_client(config: &Config) -> Result<Client, String> {
+    let session = Session::load_or_create(&config.session)
+        .map_err(|e| format!("Can't open {}: {}", config.session.display(), e))?;
+    Client::connect(ClientConfig {
+        session,
+        api_id: config.api_id,
+        api_hash: config.api_hash.clone(),
+        params: Default::default(),
+    })
+    .await
+    .map_err(|e| format!("Can't connect to telegram: {}", e))
+}
 
-        let mut client = Client::connect(Config {
-            session: Session::load_or_create("fpfs.session").unwrap(),
-            api_id,
-            api_hash: api_hash.clone(),
-            params: Default::default(),
-        })
+/// Whether the session of the client is signed in.
+pub async fn is_signed_in(client: &mut Client) -> Result<bool, String> {
+    client
+        .is_authorized()
         .await
-        .unwrap();
+        .map_err(|e| format!("Can't check the session: {}", e))
+}
 
-        if !client.is_authorized().await.unwrap() {
-            panic!("Panic")
+impl TgConnection<EncryptedChat<TgChat>> {
+    /// Connect to the chat from the config, the client runs on the current runtime.
+    ///
+    /// The disk cache keeps the ids of the meta message and the key record between mounts.
+    ///   Without the encryption key the chat is checked for the key record, so an encrypted
+    ///   filesystem fails the connection instead of being mounted as a new plain one.
+    pub async fn connect(
+        config: &Config,
+        disk_cache: Option<Arc<DiskCache>>,
+    ) -> Result<TgConnection<EncryptedChat<TgChat>>, String> {
+        let mut client = connect_client(config).await?;
+
+        if !is_signed_in(&mut client).await? {
+            return Err("The session is not signed in, run `fpfs login` first".to_string());
         }
 
-        let client_handler = client.handle();
+        let peer = resolve_peer(&mut client, &config.chat).await?;
+        let chat =
+            TgChat::new(client.handle(), peer).with_request_budget(config.requests_per_minute);
+
+        // Requests of the handle are sent by the running client
+        tokio::spawn(async move { client.run_until_disconnected().await });
+
+        let chat = match config.encryption_secret()? {
+            Some(secret) => {
+                let known_record = disk_cache.as_ref().and_then(|x| x.key_id());
+                let (chat, record) = EncryptedChat::unlock(chat, &secret, known_record)
+                    .await
+                    .map_err(|e| format!("Can't unlock the filesystem: {}", e))?;
+                if let Some(disk_cache) = &disk_cache {
+                    disk_cache
+                        .save_key_id(record)
+                        .map_err(|e| format!("Can't save the key record id: {}", e))?;
+                }
+                chat
+            }
+            None => {
+                // The cache may be left by another filesystem, only the meta message of the same
+                //   one counts. Without the id in the cache, the pinned meta message is enough
+                let known_meta = disk_cache.as_ref().and_then(|x| x.meta_id());
+                let storage = disk_cache.as_ref().and_then(|x| x.storage_id());
+                let is_meta = |text: &str| {
+                    parse_meta(text).map_or(false, |x| storage.map_or(true, |id| id == x.id))
+                };
+                EncryptedChat::open_plain(chat, known_meta, &is_meta)
+                    .await
+                    .map_err(|e| format!("Can't open the filesystem: {}", e))?
+            }
+        };
 
-        return (TgConnection { client_handler }, client);
+        let connection = TgConnection::with_chat(chat);
+        Ok(match disk_cache {
+            Some(data) => connection.with_disk_cache(data),
+            None => connection,
+        })
     }
+}
 
-    #[tokio::main]
-    pub async fn check_or_init_meta(&mut self, root_attr: &FileAttr) {
-        let (_, meta) = self.get_or_create_meta_message().await;
-        if meta.files.is_empty() {
-            self.do_create_dir("", root_attr.ino, None, root_attr).await;
-            self.edit_meta_message(&|x: &mut MetaMessage| x.next_ino = root_attr.ino + 1)
-                .await;
+impl<C: Chat> TgConnection<C> {
+    pub fn with_chat(chat: C) -> TgConnection<C> {
+        TgConnection {
+            chat,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            compression: false,
+            content_index: std::sync::Mutex::new(HashMap::new()),
+            meta_lock: Mutex::new(()),
+            lease: std::sync::Mutex::new(0..0),
+            meta_id: AtomicI32::new(0),
+            meta_stamp: AtomicU64::new(0),
+            storage_id: AtomicU64::new(0),
+            disk_cache: None,
         }
     }
 
-    #[tokio::main]
-    pub async fn create_file(&mut self, name: &str, ino: u64, parent: u64, attr: &FileAttr) {
-        let mut client_handle = &mut self.client_handler;
-        let peer_into = TgConnection::get_peer();
+    /// Split the files into smaller chunks, the tests use it to get many chunks from small files.
+    #[allow(dead_code)]
+    pub fn with_chunk_size(mut self, chunk_size: u64) -> TgConnection<C> {
+        self.chunk_size = chunk_size;
+        self
+    }
 
-        let new_file_link = FileLink::new_file(name.to_string(), attr.clone());
+    /// Compress the content of the files with zstd, unless `COMPRESSION_XATTR` says otherwise.
+    pub fn with_compression(mut self, compression: bool) -> TgConnection<C> {
+        self.compression = compression;
+        self
+    }
 
-        let attr_message = to_string(&new_file_link).unwrap();
-        let message: InputMessage = attr_message.into();
-        client_handle
-            .send_message(&peer_into, message)
-            .await
-            .unwrap();
-        let attr_message_id = last_message(&mut client_handle, &peer_into).await;
+    /// Keep the content of the files in the local cache.
+    pub fn with_disk_cache(mut self, disk_cache: Arc<DiskCache>) -> TgConnection<C> {
+        if let Some(id) = disk_cache.meta_id() {
+            *self.meta_id.get_mut() = id;
+        }
+        self.disk_cache = Some(disk_cache);
+        self
+    }
 
-        let new_text = |text: &mut MetaMessage| {
-            text.files.insert(ino.clone(), attr_message_id);
+    /// `None` if the chat doesn't have a filesystem yet.
+    pub async fn status(&self) -> FsResult<Option<StorageStatus>> {
+        let meta = match self.get_meta_message().await? {
+            Some((_, data)) => data,
+            None => return Ok(None),
         };
 
-        self.edit_meta_message(&new_text).await;
+        let shard_ids = self.get_all_shard_ids(&meta).await?;
+        let shards = self.get_shards(&shard_ids).await?;
+        let chunks: usize = shards.iter().map(|x| x.refs.len()).sum();
+        let inodes = shards.iter().map(|x| x.files.len()).sum::<usize>() - chunks;
+
+        Ok(Some(StorageStatus {
+            version: meta.version,
+            shards: shard_ids.len(),
+            inodes,
+            chunks,
+        }))
+    }
 
-        self.add_child(ino, &parent).await;
+    /// `FsError::Unsupported` if the chat keeps a filesystem of another format.
+    pub async fn check_version(&self) -> FsResult<()> {
+        self.get_meta_message().await.map(|_| ())
     }
 
-    async fn add_child(&mut self, child: u64, parent: &u64) {
-        let peer_into = TgConnection::get_peer();
+    async fn get_link(&self, ino: u64) -> FsResult<(i32, FileLink)> {
+        let file_msg_id = self.get_message_id(ino).await?;
+        let message = get_message(&self.chat, file_msg_id).await?;
+        let link = from_str(&message.text)?;
+        Ok((message.id, link))
+    }
+
+    /// Upload the file chunk by chunk, every chunk to its own message.
+    ///
+    /// Compressed chunks list the sizes of their frames, so they are not larger than
+    ///   `MAX_CACHED_DOWNLOAD` to keep the list short. Chunks that don't compress well
+    ///   are stored as is.
+    ///
+    /// Chunks with the content known from the content index aren't uploaded again.
+    async fn upload_chunks(&self, path: &Path, compress: bool) -> FsResult<Vec<FileChunk>> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let max_chunk_size = if compress {
+            self.chunk_size.min(MAX_CACHED_DOWNLOAD)
+        } else {
+            self.chunk_size
+        };
 
-        let (_, meta) = self.get_meta_message().await.unwrap();
-        let parent_id = meta.files.get(&parent).unwrap();
+        let mut chunks = vec![];
+        while (chunks.len() as u64) * max_chunk_size < size {
+            let mut chunk_file = NamedTempFile::new()?;
+            let chunk_size = io::copy(&mut file.by_ref().take(max_chunk_size), &mut chunk_file)?;
 
-        let mut client_handle = &mut self.client_handler;
-        let message = get_message(&mut client_handle, parent_id.clone()).await;
-        let mut dir_attrs: FileLink = from_str(&message.text()).unwrap();
-        dir_attrs.children.push(child);
+            let hash = file_hash(chunk_file.path())?;
+            if let Some(chunk) = self.reuse_chunk(&hash, chunk_size).await? {
+                chunks.push(chunk);
+                continue;
+            }
 
-        let first_msg = to_string(&dir_attrs).unwrap();
-        let second_msg = to_string(&dir_attrs).unwrap();
+            let compressed = if compress {
+                compression::compress(chunk_file.path())?
+            } else {
+                None
+            };
+            let upload_path = match &compressed {
+                Some(data) => data.file.path(),
+                None => chunk_file.path(),
+            };
+
+            let uploaded = self.chat.upload_file(upload_path).await?;
+            let file_id = uploaded.id;
+            if let Some(disk_cache) = &self.disk_cache {
+                disk_cache.insert(file_id, chunk_file.path());
+            }
 
-        edit_or_recreate(
-            message.id(),
-            first_msg.into(),
-            second_msg.into(),
-            &mut client_handle,
-            &peer_into,
-        )
-        .await;
+            let text = chunk_text(&hash);
+            let message_id = self.chat.send_message(text, Some(uploaded)).await?;
+            let ino = {
+                let _guard = self.meta_lock.lock().await;
+                self.register_chunk(message_id).await?
+            };
+
+            let chunk = FileChunk {
+                ino,
+                message_id,
+                size: chunk_size,
+                file_id,
+                compressed: compressed.is_some(),
+                frames: compressed.map(|x| x.frames).unwrap_or_default(),
+                hash,
+            };
+            self.remember_chunk(&chunk)?;
+            chunks.push(chunk);
+        }
+        Ok(chunks)
     }
 
-    async fn remove_child(&mut self, child: u64, parent: &u64) {
-        let peer_into = TgConnection::get_peer();
+    /// The known chunk with the same content, its reference count is increased.
+    async fn reuse_chunk(&self, hash: &str, size: u64) -> FsResult<Option<FileChunk>> {
+        let known = self.content_index.lock().unwrap().get(hash).cloned();
+        let chunk = match known {
+            Some(data) if data.size == size => data,
+            _ => return Ok(None),
+        };
+
+        // The index may be outdated, the message is checked to be the chunk with this content
+        let message = self.chat.get_messages(&[chunk.message_id]).await?.remove(0);
+        if message.map(|x| x.text) != Some(chunk_text(hash)) {
+            self.forget_chunk(&chunk)?;
+            return Ok(None);
+        }
+
+        let _guard = self.meta_lock.lock().await;
+        match self.change_refs(&chunk, 1).await {
+            Ok(Some(_)) => Ok(Some(chunk)),
+            Ok(None) | Err(FsError::NotFound) => {
+                self.forget_chunk(&chunk)?;
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
 
-        let (_, meta) = self.get_meta_message().await.unwrap();
-        let parent_id = meta.files.get(&parent).unwrap();
+    /// The files don't point to the chunks anymore, the chunks nobody else points to are deleted.
+    ///   Should be called under `meta_lock`.
+    async fn release_chunks(&self, chunks: &[FileChunk]) -> FsResult<()> {
+        let mut to_delete = vec![];
+        for chunk in chunks {
+            // If the count can't be changed, the chunk is kept, so no file loses its content
+            match self.change_refs(chunk, -1).await {
+                Ok(Some(0)) => {
+                    self.forget_chunk(chunk)?;
+                    to_delete.push(chunk.message_id);
+                }
+                Ok(_) | Err(FsError::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
 
-        let mut client_handle = &mut self.client_handler;
-        let message = get_message(&mut client_handle, parent_id.clone()).await;
-        let mut dir_attrs: FileLink = from_str(&message.text()).unwrap();
-        dir_attrs.children.retain(|x| x != &child);
+        if !to_delete.is_empty() {
+            self.chat.delete_messages(&to_delete).await?;
+        }
+        Ok(())
+    }
 
-        let first_msg = to_string(&dir_attrs).unwrap();
-        let second_msg = to_string(&dir_attrs).unwrap();
+    /// Change the reference count of the chunk in its shard. The chunk without references
+    ///   is removed from the inode table, its message is deleted by the caller. `None` if the
+    ///   chunk isn't in the table, e.g. another mount has just removed it.
+    async fn change_refs(&self, chunk: &FileChunk, delta: i64) -> FsResult<Option<u32>> {
+        let ino = chunk.ino;
+        let number = Self::shard_number(ino);
+        // The shard isn't created for an inode that was never allocated
+        let shard_id = match self.find_shard(number).await? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        self.edit_shard_message(number, shard_id, &|shard: &mut MetaShard| {
+            if shard.files.get(&ino) != Some(&chunk.message_id) {
+                return Ok(None);
+            }
 
-        edit_or_recreate(
-            message.id(),
-            first_msg.into(),
-            second_msg.into(),
-            &mut client_handle,
-            &peer_into,
-        )
-        .await;
+            let refs = (*shard.refs.get(&ino).unwrap_or(&0) as i64 + delta).max(0) as u32;
+            if refs > 0 {
+                shard.refs.insert(ino, refs);
+            } else {
+                shard.refs.remove(&ino);
+                shard.files.remove(&ino);
+            }
+            Ok(Some(refs))
+        })
+        .await
     }
 
-    async fn update_file(&mut self, inode: u64, updater: &dyn Fn(&mut FileLink) -> ()) {
-        let peer_into = TgConnection::get_peer();
+    fn remember_chunk(&self, chunk: &FileChunk) -> FsResult<()> {
+        let mut index = self.content_index.lock().unwrap();
+        if index.contains_key(&chunk.hash) {
+            return Ok(());
+        }
+        index.insert(chunk.hash.clone(), chunk.clone());
+        self.save_content_index(&index)
+    }
 
-        let (_, meta) = self.get_meta_message().await.unwrap();
-        let parent_id = meta.files.get(&inode).unwrap();
+    fn forget_chunk(&self, chunk: &FileChunk) -> FsResult<()> {
+        let mut index = self.content_index.lock().unwrap();
+        if index.remove(&chunk.hash).is_some() {
+            self.save_content_index(&index)?;
+        }
+        Ok(())
+    }
 
-        let mut client_handle = &mut self.client_handler;
-        let message = get_message(&mut client_handle, parent_id.clone()).await;
-        let mut dir_attrs: FileLink = from_str(&message.text()).unwrap();
+    /// The index is kept by the id of the filesystem, it's known after `check_or_init_meta`.
+    fn save_content_index(&self, index: &HashMap<String, FileChunk>) -> FsResult<()> {
+        let storage = self.storage_id.load(Ordering::SeqCst);
+        if let Some(disk_cache) = &self.disk_cache {
+            if storage != 0 {
+                disk_cache.save_content_index(storage, index)?;
+            }
+        }
+        Ok(())
+    }
 
-        updater(&mut dir_attrs);
+    /// Read the part of the chunk from the disk cache or download it. Without the disk cache
+    ///   only the frames of compressed chunks that overlap with the range are downloaded.
+    async fn read_chunk(&self, chunk: &FileChunk, offset: u64, size: u64) -> FsResult<Vec<u8>> {
+        // Files written by other devices may be copied here later
+        self.remember_chunk(chunk)?;
 
-        let first_msg = to_string(&dir_attrs).unwrap();
-        let second_msg = to_string(&dir_attrs).unwrap();
+        let disk_cache = self.disk_cache.as_ref();
 
-        if let Some(data) = dir_attrs.file {
-            let first_full_msg = InputMessage::from(first_msg).file(data.clone().into());
-            let second_msg_full = InputMessage::from(second_msg).file(data.into());
+        if let Some(data) = disk_cache.and_then(|x| x.read(chunk.file_id, offset, size)) {
+            return Ok(data);
+        }
+        if chunk.compressed && !chunk.frames.is_empty() && disk_cache.is_none() {
+            return self.read_frames(chunk, offset, size).await;
+        }
+        if !chunk.compressed && (disk_cache.is_none() || chunk.size > MAX_CACHED_DOWNLOAD) {
+            return self
+                .chat
+                .download_range(chunk.message_id, offset, size)
+                .await;
+        }
 
-            edit_or_recreate(
-                message.id(),
-                first_full_msg,
-                second_msg_full,
-                &mut client_handle,
-                &peer_into,
-            )
-            .await;
-        } else {
-            edit_or_recreate(
-                message.id(),
-                first_msg.into(),
-                second_msg.into(),
-                &mut client_handle,
-                &peer_into,
-            )
-            .await;
+        let mut data = self.chat.download_media(chunk.message_id).await?;
+        if chunk.compressed {
+            data = compression::decompress(&data).map_err(|e| FsError::Corrupted(e.to_string()))?;
+        }
+        if let Some(disk_cache) = disk_cache {
+            disk_cache.insert_data(chunk.file_id, &data);
         }
+
+        let start = (offset as usize).min(data.len());
+        let end = ((offset + size) as usize).min(data.len());
+        Ok(data[start..end].to_vec())
     }
 
-    #[tokio::main]
-    pub async fn create_dir(&mut self, name: &str, ino: u64, parent: Option<u64>, attr: &FileAttr) {
-        self.do_create_dir(name, ino, parent, attr).await
+    /// Download and decompress the frames of the compressed chunk that overlap with the range.
+    async fn read_frames(&self, chunk: &FileChunk, offset: u64, size: u64) -> FsResult<Vec<u8>> {
+        let end = (offset + size).min(chunk.size);
+        if offset >= end {
+            return Ok(vec![]);
+        }
+
+        let first = (offset / FRAME_SIZE) as usize;
+        let last = ((end - 1) / FRAME_SIZE) as usize;
+        let frames = chunk.frames.get(first..=last).ok_or_else(|| {
+            FsError::Corrupted(format!("Chunk {} lacks frame {}", chunk.ino, last))
+        })?;
+        let compressed_offset: u64 = chunk.frames[..first].iter().map(|&x| x as u64).sum();
+        let compressed_size: u64 = frames.iter().map(|&x| x as u64).sum();
+
+        let compressed = self
+            .chat
+            .download_range(chunk.message_id, compressed_offset, compressed_size)
+            .await?;
+        let data =
+            compression::decompress(&compressed).map_err(|e| FsError::Corrupted(e.to_string()))?;
+
+        let frames_start = first as u64 * FRAME_SIZE;
+        let to = ((end - frames_start) as usize).min(data.len());
+        let from = ((offset - frames_start) as usize).min(to);
+        Ok(data[from..to].to_vec())
     }
 
-    #[tokio::main]
-    pub async fn set_attr(&mut self, ino: u64, attr: FileAttr) {
-        self.update_file(ino, &|file: &mut FileLink| file.attr = attr)
-            .await;
+    /// Add the child to the last page of the directory, or to a new page if the last one is full.
+    async fn add_child(&self, child: u64, name: &str, parent: &u64) -> FsResult<()> {
+        let (message_id, mut dir_attrs) = self.get_link(*parent).await?;
+
+        if let Some(&page_id) = dir_attrs.pages.last() {
+            let page_message = get_message(&self.chat, page_id).await?;
+            // The page is left as is if the child doesn't fit
+            let editor = |text: &str| -> FsResult<(String, bool)> {
+                let mut page: DirPage = from_prefixed_str(DIR_CONSTANT, text)?;
+                if !page
+                    .entries
+                    .iter()
+                    .any(|x| x.ino == child && x.name == name)
+                {
+                    page.entries.push(DirEntry {
+                        ino: child,
+                        name: name.to_string(),
+                    });
+                }
+
+                let new_text = to_prefixed_string(DIR_CONSTANT, &page)?;
+                if self.chat.text_length(&new_text) <= MAX_PAGE_LENGTH {
+                    Ok((new_text, true))
+                } else {
+                    Ok((text.to_string(), false))
+                }
+            };
+
+            let (added, recreated) = self.edit_checked(page_message, &editor).await?;
+            if let Some(new_id) = recreated {
+                *dir_attrs.pages.last_mut().unwrap() = new_id;
+                self.save_link(*parent, message_id, &dir_attrs).await?;
+            }
+            if added {
+                return Ok(());
+            }
+        }
+
+        let page = DirPage {
+            entries: vec![DirEntry {
+                ino: child,
+                name: name.to_string(),
+            }],
+        };
+        let text = to_prefixed_string(DIR_CONSTANT, &page)?;
+        let page_id = self.chat.send_message(text, None).await?;
+
+        dir_attrs.pages.push(page_id);
+        self.save_link(*parent, message_id, &dir_attrs).await
     }
 
-    #[tokio::main]
-    pub async fn set_xattr(&mut self, ino: u64, name: String, data: Vec<u8>) {
-        self.update_file(ino, &|file: &mut FileLink| {
-            file.xattr.insert(name.clone(), data.clone());
-        })
-        .await;
+    /// Remove the name of the child from its page. Pages that become empty are deleted.
+    async fn remove_child(&self, child: &FileLink, name: &str, parent: &u64) -> FsResult<()> {
+        let (message_id, mut dir_attrs) = self.get_link(*parent).await?;
+
+        let ino = child.attr.ino;
+        let is_entry = |page: &DirPage| page.entries.iter().any(|x| x.ino == ino && x.name == name);
+
+        let pages = self.chat.get_messages(&dir_attrs.pages).await?;
+        for (index, message) in pages.into_iter().enumerate() {
+            let message = match message {
+                Some(data) => data,
+                None => continue,
+            };
+            let page: Option<DirPage> = from_prefixed_str(DIR_CONSTANT, &message.text).ok();
+            if !page.map_or(false, |x| is_entry(&x)) {
+                continue;
+            }
+
+            // The last child is removed together with the page
+            let editor = |text: &str| -> FsResult<(String, bool)> {
+                let mut page: DirPage = from_prefixed_str(DIR_CONSTANT, text)?;
+                page.entries.retain(|x| x.ino != ino || x.name != name);
+                if page.entries.is_empty() {
+                    Ok((text.to_string(), true))
+                } else {
+                    Ok((to_prefixed_string(DIR_CONSTANT, &page)?, false))
+                }
+            };
+
+            let (empty, recreated) = self.edit_checked(message, &editor).await?;
+            if empty {
+                self.chat.delete_messages(&[dir_attrs.pages[index]]).await?;
+                dir_attrs.pages.remove(index);
+                self.save_link(*parent, message_id, &dir_attrs).await?;
+            } else if let Some(new_id) = recreated {
+                dir_attrs.pages[index] = new_id;
+                self.save_link(*parent, message_id, &dir_attrs).await?;
+            }
+            return Ok(());
+        }
+        Ok(())
     }
 
-    #[tokio::main]
-    pub async fn remove_xattr(&mut self, ino: u64, name: String) {
-        self.update_file(ino, &|file: &mut FileLink| {
-            file.xattr.remove(name.as_str());
-        })
-        .await;
+    /// Chunks of the file, in order.
+    async fn get_chunks(&self, link: &FileLink) -> FsResult<Vec<FileChunk>> {
+        if link.chunk_pages.is_empty() {
+            return Ok(link.chunks.clone());
+        }
+
+        let mut chunks = vec![];
+        for message in self.chat.get_messages(&link.chunk_pages).await? {
+            let message = message.ok_or(FsError::NotFound)?;
+            let page: ChunkPage = from_prefixed_str(CHUNKS_CONSTANT, &message.text)?;
+            chunks.extend(page.chunks);
+        }
+        Ok(chunks)
+    }
+
+    /// Put the chunk list into the link, or into new `[CHUNKS]` pages if it's long. Returns
+    ///   the pages of the previous list, they are deleted once the link is saved.
+    async fn set_chunks(&self, link: &mut FileLink, chunks: Vec<FileChunk>) -> FsResult<Vec<i32>> {
+        let old_pages = std::mem::take(&mut link.chunk_pages);
+        if to_string(&chunks)?.len() <= MAX_INLINE_CHUNKS_LENGTH {
+            link.chunks = chunks;
+            return Ok(old_pages);
+        }
+
+        link.chunks = vec![];
+        let mut page = ChunkPage { chunks: vec![] };
+        for chunk in chunks {
+            page.chunks.push(chunk);
+            let text = to_prefixed_string(CHUNKS_CONSTANT, &page)?;
+            if self.chat.text_length(&text) > MAX_PAGE_LENGTH && page.chunks.len() > 1 {
+                let next = page.chunks.pop().unwrap();
+                let text = to_prefixed_string(CHUNKS_CONSTANT, &page)?;
+                let page_id = self.chat.send_message(text, None).await?;
+                link.chunk_pages.push(page_id);
+                page.chunks = vec![next];
+            }
+        }
+        let text = to_prefixed_string(CHUNKS_CONSTANT, &page)?;
+        let page_id = self.chat.send_message(text, None).await?;
+        link.chunk_pages.push(page_id);
+        Ok(old_pages)
     }
 
-    #[tokio::main]
-    pub async fn rename(&mut self, ino: u64, new_name: &str, parent: u64, new_parent: u64) {
-        let updater = |file: &mut FileLink| file.name = new_name.to_string();
-        self.update_file(ino, &updater).await;
+    async fn get_page(&self, id: i32) -> FsResult<DirPage> {
+        let message = get_message(&self.chat, id).await?;
+        Ok(from_prefixed_str(DIR_CONSTANT, &message.text)?)
+    }
 
-        self.remove_child(ino, &parent).await;
-        self.add_child(ino, &new_parent).await;
+    /// Entries of all the pages of the directory, the pages are read at once.
+    async fn get_entries(&self, directory: &FileLink) -> FsResult<Vec<DirEntry>> {
+        let mut entries = vec![];
+        for message in self.chat.get_messages(&directory.pages).await? {
+            let message = message.ok_or(FsError::NotFound)?;
+            let page: DirPage = from_prefixed_str(DIR_CONSTANT, &message.text)?;
+            entries.extend(page.entries);
+        }
+        Ok(entries)
     }
 
-    async fn do_create_dir(&mut self, name: &str, ino: u64, parent: Option<u64>, attr: &FileAttr) {
-        let mut client_handle = &mut self.client_handler;
-        let peer_into = TgConnection::get_peer();
+    /// Links of the directory entries named as in the entries, the entries of the removed
+    ///   inodes are skipped.
+    async fn get_entry_links(&self, entries: Vec<DirEntry>) -> FsResult<Vec<FileLink>> {
+        let inodes: Vec<u64> = entries.iter().map(|x| x.ino).collect();
+        let file_ids = self.get_message_ids(&inodes).await?;
 
-        let new_file_link = FileLink::new_dir(name.to_string(), vec![], attr.clone());
+        let links: HashMap<u64, FileLink> = self
+            .chat
+            .get_messages(&file_ids)
+            .await?
+            .iter()
+            .filter_map(|x| x.as_ref().and_then(|t| from_str::<FileLink>(&t.text).ok()))
+            .map(|x| (x.attr.ino, x))
+            .collect();
 
-        let attr_message = to_string(&new_file_link).unwrap();
-        let message: InputMessage = attr_message.into();
-        client_handle
-            .send_message(&peer_into, message)
-            .await
-            .unwrap();
-        let attr_message_id = last_message(&mut client_handle, &peer_into).await;
+        // The same inode may be listed under several names
+        Ok(entries
+            .into_iter()
+            .filter_map(|entry| {
+                let mut link = links.get(&entry.ino)?.clone();
+                link.name = entry.name;
+                Some(link)
+            })
+            .collect())
+    }
 
-        let new_text = |text: &mut MetaMessage| {
-            text.files.insert(ino, attr_message_id);
-        };
+    async fn update_file(
+        &self,
+        inode: u64,
+        updater: &(dyn Fn(&mut FileLink) + Sync),
+    ) -> FsResult<()> {
+        let (message_id, mut dir_attrs) = self.get_link(inode).await?;
 
-        if let Some(parent_ino) = parent {
-            self.add_child(ino, &parent_ino).await;
-        }
+        updater(&mut dir_attrs);
 
-        self.edit_meta_message(&new_text).await;
+        self.save_link(inode, message_id, &dir_attrs).await
     }
 
-    async fn edit_meta_message<F>(&mut self, f: &dyn Fn(&mut MetaMessage) -> F) -> F {
-        let (id, mut meta_message) = self.get_or_create_meta_message().await;
+    /// Write the link into the message `id`. If the message had to be recreated,
+    ///   the inode table is updated with the new id.
+    async fn save_link(&self, ino: u64, id: i32, link: &FileLink) -> FsResult<()> {
+        let text = to_string(link)?;
+        let recreated = edit_or_recreate(id, text, None, &self.chat).await?;
 
-        let res = f(&mut meta_message);
+        if let Some(new_id) = recreated {
+            self.set_message_id(ino, Some(new_id)).await?;
+        }
+        Ok(())
+    }
+
+    async fn do_create_dir(
+        &self,
+        name: &str,
+        ino: u64,
+        parent: Option<u64>,
+        attr: &FileAttr,
+    ) -> FsResult<()> {
+        let mut new_file_link = FileLink::new(name.to_string(), attr.clone(), None);
+        if let Some(parent_ino) = parent {
+            let (_, parent_link) = self.get_link(parent_ino).await?;
+            compression::inherit(&parent_link, &mut new_file_link);
+        }
 
-        let new_text = TgConnection::make_meta_string_message(&meta_message);
+        let attr_message = to_string(&new_file_link)?;
+        let attr_message_id = self.chat.send_message(attr_message, None).await?;
+        self.register_link(ino, attr_message_id).await?;
 
-        let mut client_handle = &mut self.client_handler;
-        let peer_into = TgConnection::get_peer();
-        edit_or_recreate(
-            id,
-            new_text.as_str().into(),
-            new_text.as_str().into(),
-            &mut client_handle,
-            &peer_into,
-        )
-        .await;
-        res
+        match parent {
+            Some(parent_ino) => self.add_child(ino, name, &parent_ino).await,
+            None => Ok(()),
+        }
     }
 
-    // #[tokio::main]
-    pub async fn read_file(&mut self, ino: u64) -> Option<Vec<u8>> {
-        let (_, message) = self.get_meta_message().await?;
+    fn shard_number(ino: u64) -> usize {
+        (ino / SHARD_SIZE) as usize
+    }
 
-        let meta_id = message.files.get(&ino)?;
+    /// Id of the message with the link of the inode, `FsError::NotFound` if there is no such inode.
+    async fn get_message_id(&self, ino: u64) -> FsResult<i32> {
+        let (_, meta) = self.get_or_create_meta_message().await?;
 
-        let client_handle = &mut self.client_handler;
+        let shard_ids = self
+            .get_shard_ids(&meta, &[Self::shard_number(ino)])
+            .await?;
+        let shard_id = *shard_ids.first().ok_or(FsError::NotFound)?;
+        let shard = self.get_shard(shard_id).await?;
+        shard.files.get(&ino).cloned().ok_or(FsError::NotFound)
+    }
 
-        let file_message = client_handle
-            .get_messages_by_id(None, &[meta_id.clone()])
-            .await
-            .ok()?
-            .into_iter()
-            .nth(0)??;
+    /// Ids of the messages with the links of the inodes, every needed shard is read only once.
+    async fn get_message_ids(&self, inodes: &[u64]) -> FsResult<Vec<i32>> {
+        let (_, meta) = self.get_or_create_meta_message().await?;
 
-        let media: tl::enums::MessageMedia = file_message.media()?;
-        let file_location: tl::enums::InputFileLocation = media.to_input_file()?;
+        let mut numbers: Vec<usize> = inodes.iter().map(|x| Self::shard_number(*x)).collect();
+        numbers.sort();
+        numbers.dedup();
+        let shard_ids = self.get_shard_ids(&meta, &numbers).await?;
 
-        let mut download_iter = client_handle.iter_download(file_location);
-        let file = download_iter.next().await.ok()??;
+        let files: HashMap<u64, i32> = self
+            .get_shards(&shard_ids)
+            .await?
+            .into_iter()
+            .flat_map(|x| x.files)
+            .collect();
 
-        Some(file)
+        Ok(inodes
+            .iter()
+            .filter_map(|x| files.get(x).cloned())
+            .collect())
     }
 
-    pub async fn get_directory_files(&mut self, parent: &u64) -> Vec<FileLink> {
-        let (_, text) = self.get_or_create_meta_message().await;
+    /// Ids of the existing shards with the given numbers, every needed index message
+    ///   is read only once.
+    async fn get_shard_ids(&self, meta: &MetaMessage, numbers: &[usize]) -> FsResult<Vec<i32>> {
+        let mut index_numbers: Vec<usize> = numbers
+            .iter()
+            .map(|x| x / INDEX_SIZE)
+            .filter(|x| *x < meta.index.len())
+            .collect();
+        index_numbers.sort();
+        index_numbers.dedup();
+        let index_ids: Vec<i32> = index_numbers.iter().map(|x| meta.index[*x]).collect();
+
+        let messages = self.chat.get_messages(&index_ids).await?;
+        let indexes: HashMap<usize, MetaIndex> = index_numbers
+            .into_iter()
+            .zip(messages)
+            .filter_map(|(number, message)| {
+                let index = from_prefixed_str(INDEX_CONSTANT, &message?.text).ok()?;
+                Some((number, index))
+            })
+            .collect();
 
-        let mut client_handle = &mut self.client_handler;
+        Ok(numbers
+            .iter()
+            .filter_map(|x| indexes.get(&(x / INDEX_SIZE))?.shards.get(x % INDEX_SIZE))
+            .cloned()
+            .collect())
+    }
 
-        let directory_msg_id = text.files.get(parent).unwrap();
-        let directory_msg = get_message(&mut client_handle, directory_msg_id.clone()).await;
-        let directory: FileLink = from_str(directory_msg.text()).unwrap();
-        let file_ids: Vec<i32> = directory
-            .children
+    /// Ids of all the shards of the inode table.
+    async fn get_all_shard_ids(&self, meta: &MetaMessage) -> FsResult<Vec<i32>> {
+        Ok(self
+            .chat
+            .get_messages(&meta.index)
+            .await?
             .iter()
-            .map(|x| text.files.get(x).unwrap().clone())
-            .collect();
+            .filter_map(|x| {
+                x.as_ref()
+                    .and_then(|t| from_prefixed_str::<MetaIndex>(INDEX_CONSTANT, &t.text).ok())
+            })
+            .flat_map(|x| x.shards)
+            .collect())
+    }
 
-        client_handle
-            .get_messages_by_id(None, &file_ids)
-            .await
-            .unwrap_or(vec![])
+    /// The shards that can be read, the missing ones are skipped.
+    async fn get_shards(&self, ids: &[i32]) -> FsResult<Vec<MetaShard>> {
+        Ok(self
+            .chat
+            .get_messages(ids)
+            .await?
             .iter()
-            .filter_map(|x| match x {
-                None => None,
-                Some(t) => from_str(t.text()).unwrap(),
+            .filter_map(|x| {
+                x.as_ref()
+                    .and_then(|t| from_prefixed_str::<MetaShard>(SHARD_CONSTANT, &t.text).ok())
             })
-            .collect()
+            .collect())
     }
 
-    pub async fn get_file_attr(&mut self, ino: &u64) -> Option<FileLink> {
-        let (_, text) = self.get_or_create_meta_message().await;
+    /// Record the id of the message with the link of the inode, `None` removes the inode.
+    ///   Only the shard of the inode is edited, the index and the meta message are changed only
+    ///   when a shard is created or recreated.
+    async fn set_message_id(&self, ino: u64, message_id: Option<i32>) -> FsResult<()> {
+        self.edit_shard(ino, &|shard: &mut MetaShard| {
+            match message_id {
+                Some(id) => shard.files.insert(ino, id),
+                None => shard.files.remove(&ino),
+            };
+            Ok(())
+        })
+        .await
+    }
 
-        let mut client_handle = &mut self.client_handler;
+    /// Record the message of a new inode, `refs` is the reference count of a new chunk.
+    ///
+    /// `FsError::Conflict` if another mount has already recorded the same inode, then
+    ///   the lease is dropped, so the next inode is taken from a new lease.
+    async fn register_inode(&self, ino: u64, message_id: i32, refs: Option<u32>) -> FsResult<()> {
+        let result = self
+            .edit_shard(ino, &|shard: &mut MetaShard| match shard.files.get(&ino) {
+                Some(&id) if id != message_id => Err(FsError::Conflict(format!(
+                    "inode {} is allocated by another mount",
+                    ino
+                ))),
+                _ => {
+                    shard.files.insert(ino, message_id);
+                    if let Some(data) = refs {
+                        shard.refs.insert(ino, data);
+                    }
+                    Ok(())
+                }
+            })
+            .await;
 
-        let file_msg_id = text.files.get(ino)?;
-        let message = get_message(&mut client_handle, file_msg_id.clone()).await;
-        from_str(message.text()).ok()
+        if let Err(FsError::Conflict(_)) = &result {
+            *self.lease.lock().unwrap() = 0..0;
+        }
+        result
     }
 
-    #[tokio::main]
-    pub async fn write_to_file(&mut self, tempfile: NamedTempFile, ino: u64) {
-        let client_handle = &mut self.client_handler;
-        let peer_into = TgConnection::get_peer();
+    /// Record the message of a new link like `register_inode`. If the inode is taken
+    ///   by another mount, the message is deleted, the link is created again with a new inode.
+    async fn register_link(&self, ino: u64, message_id: i32) -> FsResult<()> {
+        let result = self.register_inode(ino, message_id, None).await;
+        if let Err(FsError::Conflict(_)) = &result {
+            self.chat.delete_messages(&[message_id]).await?;
+        }
+        result
+    }
 
-        // Upload file
-        let path = tempfile.path().to_str().unwrap();
-        let res: tl::enums::InputFile = client_handle.upload_file(path).await.unwrap();
+    /// Inode for the message of a new chunk. The text of the message doesn't depend
+    ///   on the inode, so the next inode is tried if another mount takes the same one.
+    ///   Should be called under `meta_lock`.
+    async fn register_chunk(&self, message_id: i32) -> FsResult<u64> {
+        let mut attempt = 0;
+        loop {
+            let ino = self.allocate_ino().await?;
+            match self.register_inode(ino, message_id, Some(1)).await {
+                Err(FsError::Conflict(e)) if attempt < MAX_CONFLICT_RETRIES => {
+                    log::warn!("{}, taking another inode", e);
+                    attempt += 1;
+                }
+                result => return result.map(|_| ino),
+            }
+        }
+    }
 
-        // Get file message
-        let (_, message) = self.get_meta_message().await.unwrap();
+    /// Apply `edit` to the shard of the inode with `edit_checked`, the shard is created
+    ///   if needed, and the index is updated if the shard was recreated.
+    async fn edit_shard<R: Send>(
+        &self,
+        ino: u64,
+        edit: &(dyn Fn(&mut MetaShard) -> FsResult<R> + Sync),
+    ) -> FsResult<R> {
+        let number = Self::shard_number(ino);
+        let shard_id = self.get_or_create_shard(number).await?;
+        self.edit_shard_message(number, shard_id, edit).await
+    }
 
-        let file_id = message.files.get(&ino).unwrap();
+    async fn edit_shard_message<R: Send>(
+        &self,
+        number: usize,
+        shard_id: i32,
+        edit: &(dyn Fn(&mut MetaShard) -> FsResult<R> + Sync),
+    ) -> FsResult<R> {
+        let shard_message = get_message(&self.chat, shard_id).await?;
+        let editor = |text: &str| -> FsResult<(String, R)> {
+            let mut shard: MetaShard = from_prefixed_str(SHARD_CONSTANT, text)?;
+            let res = edit(&mut shard)?;
+            Ok((to_prefixed_string(SHARD_CONSTANT, &shard)?, res))
+        };
 
-        let mut client_handle = &mut self.client_handler;
-        let file_message = get_message(&mut client_handle, file_id.clone()).await;
+        let (res, recreated) = self.edit_checked(shard_message, &editor).await?;
+        if let Some(new_id) = recreated {
+            let (_, meta) = self.get_or_create_meta_message().await?;
+            let index_number = number / INDEX_SIZE;
+            let index_message = get_message(&self.chat, meta.index[index_number]).await?;
+            let position = number % INDEX_SIZE;
+            self.edit_index(index_number, index_message, &|x: &mut MetaIndex| {
+                x.shards[position] = new_id
+            })
+            .await?;
+        }
+        Ok(res)
+    }
 
-        let mut result: FileLink = from_str(file_message.text()).unwrap();
-        let file = File::open(path).unwrap();
-        result.attr.size = file.metadata().unwrap().len();
-        result.file = Some(res.clone().into());
+    /// Id of the shard, `None` if it's not created yet.
+    async fn find_shard(&self, number: usize) -> FsResult<Option<i32>> {
+        let (_, meta) = self.get_or_create_meta_message().await?;
+        let index_id = match meta.index.get(number / INDEX_SIZE) {
+            Some(&data) => data,
+            None => return Ok(None),
+        };
+        let index_message = get_message(&self.chat, index_id).await?;
+        let index: MetaIndex = from_prefixed_str(INDEX_CONSTANT, &index_message.text)?;
+        Ok(index.shards.get(number % INDEX_SIZE).cloned())
+    }
 
-        // Update file message
-        let message = InputMessage::text(to_string(&result).unwrap()).file(res.clone());
+    /// Id of the shard. Inodes are allocated in order, so the missing shards before it
+    ///   and their index messages are created in order as well.
+    async fn get_or_create_shard(&self, number: usize) -> FsResult<i32> {
+        let index_number = number / INDEX_SIZE;
+        let (_, meta) = self.get_or_create_meta_message().await?;
+
+        let mut index_ids = meta.index;
+        if index_ids.len() <= index_number {
+            let empty_index = MetaIndex { shards: vec![] };
+            let text = to_prefixed_string(INDEX_CONSTANT, &empty_index)?;
+            let new_ids = self
+                .send_copies(&text, index_number + 1 - index_ids.len())
+                .await?;
+
+            // Another mount may have added some meanwhile, then its messages are kept
+            let first = index_ids.len();
+            index_ids = self
+                .edit_meta_message(&|x: &mut MetaMessage| {
+                    let added = x.index.len().max(first) - first;
+                    x.index.extend(new_ids.iter().skip(added));
+                    x.index.clone()
+                })
+                .await?;
+            self.delete_unused(new_ids, &index_ids).await?;
+        }
 
-        // TODO Actually we can just modify the existing message, but it's not supported by grammers yet
-        let recreated_id =
-            resend_message(file_message.id(), message, &mut client_handle, &peer_into).await;
+        let index_message = get_message(&self.chat, index_ids[index_number]).await?;
+        let position = number % INDEX_SIZE;
+        let known: MetaIndex = from_prefixed_str(INDEX_CONSTANT, &index_message.text)?;
+        if let Some(&id) = known.shards.get(position) {
+            return Ok(id);
+        }
+        let first = known.shards.len();
 
-        // Update meta message if needed
-        let update = |x: &mut MetaMessage| {
-            x.files.insert(ino, recreated_id);
+        let empty_shard = MetaShard {
+            files: HashMap::new(),
+            refs: HashMap::new(),
         };
-        self.edit_meta_message(&update).await;
+        let text = to_prefixed_string(SHARD_CONSTANT, &empty_shard)?;
+        let new_ids = self.send_copies(&text, position + 1 - first).await?;
+
+        let shards = self
+            .edit_index(index_number, index_message, &|x: &mut MetaIndex| {
+                let added = x.shards.len().max(first) - first;
+                x.shards.extend(new_ids.iter().skip(added));
+                x.shards.clone()
+            })
+            .await?;
+        self.delete_unused(new_ids, &shards).await?;
+        Ok(shards[position])
     }
 
-    async fn get_or_create_meta_message(&mut self) -> (i32, MetaMessage) {
-        let meta_message = self.get_meta_message().await;
+    /// Send `count` messages with the same text, e.g. new empty shards.
+    async fn send_copies(&self, text: &str, count: usize) -> FsResult<Vec<i32>> {
+        let mut ids = vec![];
+        for _ in 0..count {
+            ids.push(self.chat.send_message(text.to_string(), None).await?);
+        }
+        Ok(ids)
+    }
 
-        let client_handle = &mut self.client_handler;
-        let peer = TgConnection::get_peer();
+    /// Delete the sent messages that weren't used, another mount's messages are used instead.
+    async fn delete_unused(&self, sent: Vec<i32>, used: &[i32]) -> FsResult<()> {
+        let unused: Vec<i32> = sent.into_iter().filter(|x| !used.contains(x)).collect();
+        if !unused.is_empty() {
+            self.chat.delete_messages(&unused).await?;
+        }
+        Ok(())
+    }
 
-        match meta_message {
-            Some(data) => data,
-            None => {
-                let meta_message = MetaMessage {
-                    version: VERSION.to_string(),
-                    files: HashMap::new(),
-                    next_ino: 0u64,
-                };
-                let initial_message = TgConnection::make_meta_string_message(&meta_message);
-                client_handle
-                    .send_message(&peer, initial_message.into())
-                    .await
-                    .unwrap();
-                self.get_meta_message().await.unwrap()
+    async fn get_shard(&self, id: i32) -> FsResult<MetaShard> {
+        let message = get_message(&self.chat, id).await?;
+        Ok(from_prefixed_str(SHARD_CONSTANT, &message.text)?)
+    }
+
+    /// Apply `edit` to the current text of the message and write the result.
+    ///
+    /// Mounts on other devices may edit the same message at the same time and telegram keeps
+    ///   the last edit only, so the message is read back after the edit. If the text is not ours,
+    ///   `edit` is applied again to the text of the other mount. So `edit` may be called several
+    ///   times and should depend only on the given text. An edit of another mount that lands
+    ///   after our read back isn't noticed, so this narrows the conflicts but doesn't exclude them.
+    ///
+    /// Returns the result of `edit` and the new id of the message if it was recreated.
+    async fn edit_checked<R: Send>(
+        &self,
+        mut message: ChatMessage,
+        edit: &(dyn Fn(&str) -> FsResult<(String, R)> + Sync),
+    ) -> FsResult<(R, Option<i32>)> {
+        let mut recreated = None;
+
+        for _ in 0..=MAX_CONFLICT_RETRIES {
+            let (text, res) = edit(&message.text)?;
+            if text == message.text {
+                return Ok((res, recreated));
+            }
+
+            if let Some(new_id) =
+                edit_or_recreate(message.id, text.clone(), None, &self.chat).await?
+            {
+                recreated = Some(new_id);
+                message.id = new_id;
+            }
+
+            message = get_message(&self.chat, message.id).await?;
+            if message.text == text {
+                return Ok((res, recreated));
             }
+            log::warn!(
+                "Message {} was changed by another mount, applying the change again",
+                message.id
+            );
         }
+
+        Err(FsError::Conflict(format!(
+            "message {} keeps being changed by another mount",
+            message.id
+        )))
     }
 
-    fn make_meta_string_message(meta: &MetaMessage) -> String {
-        let info = to_string(&meta).unwrap();
-        format!("{}\n{}", META_CONSTANT, info)
+    /// `f` sees the stamp of the message before the edit, the new one is set after it.
+    async fn edit_meta_message<F: Send>(
+        &self,
+        f: &(dyn Fn(&mut MetaMessage) -> F + Sync),
+    ) -> FsResult<F> {
+        let message = self.get_or_create_meta_chat_message().await?;
+
+        let ((res, stamp), recreated) = self
+            .edit_checked(message, &|text| {
+                let mut meta_message = parse_meta(text)?;
+                let res = f(&mut meta_message);
+                meta_message.stamp = rand::random();
+                let text = to_prefixed_string(META_CONSTANT, &meta_message)?;
+                Ok((text, (res, meta_message.stamp)))
+            })
+            .await?;
+        self.meta_stamp.store(stamp, Ordering::SeqCst);
+
+        if let Some(new_id) = recreated {
+            self.remember_meta_id(Some(new_id))?;
+            self.pin_meta(new_id).await;
+        }
+        Ok(res)
     }
 
-    pub async fn cleanup(&mut self) {
-        let meta_message = self.get_meta_message().await;
-        let client_handle = &mut self.client_handler;
-        if let Some((id, message)) = meta_message {
-            let mut messages_to_delete: Vec<i32> = message.files.values().cloned().collect();
-            messages_to_delete.push(id);
-            client_handle
-                .delete_messages(None, &messages_to_delete)
-                .await
-                .unwrap();
+    /// The next inode of the lease, a new lease is taken when it's used up.
+    ///   Should be called under `meta_lock`.
+    async fn allocate_ino(&self) -> FsResult<u64> {
+        if let Some(ino) = self.lease.lock().unwrap().next() {
+            return Ok(ino);
+        }
+
+        let mut lease = self.lease_inodes().await?;
+        let ino = lease
+            .next()
+            .ok_or_else(|| FsError::NoSpace("no free inodes in the lease".to_string()))?;
+        *self.lease.lock().unwrap() = lease;
+        Ok(ino)
+    }
+
+    /// Take the free inodes up to the end of the shard of the next free inode, so the inodes
+    ///   of different mounts get into different shards and the meta message is changed once
+    ///   for many inodes.
+    ///
+    /// Telegram has no conditional edits, so an edit sent before ours by another mount may land
+    ///   after our read back and take the same inodes. If another mount changed the meta message
+    ///   since this one did, it's read once more after `LEASE_CHECK_DELAY` and the lease is taken
+    ///   again if it was overwritten. This narrows the window, `register_inode` catches the rest.
+    async fn lease_inodes(&self) -> FsResult<Range<u64>> {
+        for _ in 0..=MAX_CONFLICT_RETRIES {
+            let known_stamp = self.meta_stamp.load(Ordering::SeqCst);
+            let (lease, previous_stamp) = self
+                .edit_meta_message(&|x: &mut MetaMessage| {
+                    let start = x.next_ino;
+                    x.next_ino = (start / SHARD_SIZE + 1) * SHARD_SIZE;
+                    (start..x.next_ino, x.stamp)
+                })
+                .await?;
+            // Nobody else changed the meta message, the mount works alone and doesn't wait
+            if previous_stamp == known_stamp {
+                return Ok(lease);
+            }
+
+            let stamp = self.meta_stamp.load(Ordering::SeqCst);
+            tokio::time::sleep(LEASE_CHECK_DELAY).await;
+            // Either our edit is still there, or a later lease was based on it
+            let (_, meta) = self.get_or_create_meta_message().await?;
+            self.meta_stamp.store(meta.stamp, Ordering::SeqCst);
+            if meta.stamp == stamp || meta.next_ino > lease.end {
+                return Ok(lease);
+            }
+            log::warn!(
+                "Inodes {:?} were leased by another mount too, leasing again",
+                lease
+            );
+        }
+
+        Err(FsError::Conflict(
+            "other mounts keep leasing the same inodes".to_string(),
+        ))
+    }
+
+    /// Edit the index message like `edit_meta_message`, the meta message is updated
+    ///   if the index message had to be recreated.
+    async fn edit_index<F: Send>(
+        &self,
+        index_number: usize,
+        message: ChatMessage,
+        f: &(dyn Fn(&mut MetaIndex) -> F + Sync),
+    ) -> FsResult<F> {
+        let (res, recreated) = self
+            .edit_checked(message, &|text| {
+                let mut index: MetaIndex = from_prefixed_str(INDEX_CONSTANT, text)?;
+                let res = f(&mut index);
+                Ok((to_prefixed_string(INDEX_CONSTANT, &index)?, res))
+            })
+            .await?;
+
+        if let Some(new_id) = recreated {
+            self.edit_meta_message(&|x: &mut MetaMessage| x.index[index_number] = new_id)
+                .await?;
         }
+        Ok(res)
+    }
+
+    async fn get_or_create_meta_message(&self) -> FsResult<(i32, MetaMessage)> {
+        let message = self.get_or_create_meta_chat_message().await?;
+        Ok((message.id, parse_meta(&message.text)?))
     }
 
-    pub async fn get_and_inc_ino(&mut self) -> u64 {
-        let editor = |msg: &mut MetaMessage| {
-            let next_ino = msg.next_ino;
-            msg.next_ino = next_ino + 1;
-            next_ino
+    /// `None` if the chat doesn't have a filesystem yet.
+    async fn get_meta_message(&self) -> FsResult<Option<(i32, MetaMessage)>> {
+        match self.get_meta_chat_message().await? {
+            Some(message) => Ok(Some((message.id, parse_meta(&message.text)?))),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_or_create_meta_chat_message(&self) -> FsResult<ChatMessage> {
+        if let Some(message) = self.get_meta_chat_message().await? {
+            return Ok(message);
+        }
+
+        let meta_message = MetaMessage {
+            version: VERSION.to_string(),
+            id: rand::random(),
+            index: vec![],
+            next_ino: 0u64,
+            stamp: rand::random(),
         };
-        self.edit_meta_message(&editor).await
+        let text = to_prefixed_string(META_CONSTANT, &meta_message)?;
+        let id = self.chat.send_message(text.clone(), None).await?;
+        self.remember_meta_id(Some(id))?;
+        self.pin_meta(id).await;
+        Ok(ChatMessage { id, text })
     }
 
-    #[tokio::main]
-    pub async fn remove_inode(&mut self, file_ino: u64, parent_ino: u64) {
-        let (_, message) = self.get_or_create_meta_message().await;
+    async fn get_meta_chat_message(&self) -> FsResult<Option<ChatMessage>> {
+        let known_id = self.meta_id.load(Ordering::SeqCst);
+        if known_id != 0 {
+            match get_message(&self.chat, known_id).await {
+                Ok(message) if message.text.starts_with(META_CONSTANT) => return Ok(Some(message)),
+                Ok(_) | Err(FsError::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
 
-        let file_message_id = message.files.get(&file_ino).unwrap();
+        // The id is unknown, or the message was removed or recreated by another mount
+        let is_meta = |msg: &str| msg.starts_with(META_CONSTANT);
+        let mut message = self.chat.find_pinned_message(&is_meta).await?;
+        if message.is_none() {
+            // The message couldn't be pinned, e.g. the account can't pin messages in the chat
+            message = self.chat.find_message(&is_meta).await?;
+            if let Some(data) = &message {
+                self.pin_meta(data.id).await;
+            }
+        }
+        self.remember_meta_id(message.as_ref().map(|x| x.id))?;
+        Ok(message)
+    }
 
-        let client_handle = &mut self.client_handler;
-        client_handle
-            .delete_messages(None, &vec![*file_message_id])
-            .await
-            .unwrap();
+    /// The meta message is pinned, so a mount without the cache finds it without searching
+    ///   the whole chat. The chat is still searched if it can't be pinned.
+    async fn pin_meta(&self, id: i32) {
+        if let Err(e) = self.chat.pin_message(id).await {
+            log::warn!("Can't pin the meta message: {}", e);
+        }
+    }
 
-        self.remove_child(file_ino, &parent_ino).await;
+    fn remember_meta_id(&self, id: Option<i32>) -> FsResult<()> {
+        self.meta_id.store(id.unwrap_or(0), Ordering::SeqCst);
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.save_meta_id(id)?;
+        }
+        Ok(())
+    }
 
-        self.edit_meta_message(&|x: &mut MetaMessage| x.files.remove(&file_ino))
-            .await;
+    /// See `StorageBackend::unlink`, should be called under `meta_lock`.
+    async fn do_unlink(&self, ino: u64, parent: u64, name: &str) -> FsResult<u32> {
+        let (file_message_id, mut link) = self.get_link(ino).await?;
+        // Empty pages are deleted, so a directory with pages has children
+        if link.attr.kind == FileType::Directory && !link.pages.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        self.remove_child(&link, name, &parent).await?;
+
+        if link.attr.kind != FileType::Directory && link.attr.nlink > 1 {
+            link.attr.nlink -= 1;
+            self.save_link(ino, file_message_id, &link).await?;
+            return Ok(link.attr.nlink);
+        }
+
+        let chunks = self.get_chunks(&link).await?;
+        let mut messages_to_delete = link.pages;
+        messages_to_delete.extend(link.chunk_pages);
+        messages_to_delete.push(file_message_id);
+
+        self.chat.delete_messages(&messages_to_delete).await?;
+        self.release_chunks(&chunks).await?;
+
+        self.set_message_id(ino, None).await?;
+        Ok(0)
+    }
+}
+
+#[async_trait]
+impl<C: Chat> StorageBackend for TgConnection<C> {
+    async fn check_or_init_meta(&self, root_attr: &FileAttr) -> FsResult<()> {
+        let _guard = self.meta_lock.lock().await;
+
+        match self.get_message_id(root_attr.ino).await {
+            Err(FsError::NotFound) => {
+                self.do_create_dir("", root_attr.ino, None, root_attr)
+                    .await?;
+                self.edit_meta_message(&|x: &mut MetaMessage| x.next_ino = root_attr.ino + 1)
+                    .await?;
+            }
+            result => {
+                result?;
+            }
+        }
+
+        let (_, meta) = self.get_or_create_meta_message().await?;
+        self.storage_id.store(meta.id, Ordering::SeqCst);
+        self.meta_stamp.store(meta.stamp, Ordering::SeqCst);
+        // The chunks of other filesystems that used the same cache aren't known here
+        if let Some(disk_cache) = &self.disk_cache {
+            *self.content_index.lock().unwrap() = disk_cache.content_index(meta.id);
+            disk_cache.save_storage_id(meta.id)?;
+        }
+        Ok(())
     }
 
-    async fn get_meta_message(&mut self) -> Option<(i32, MetaMessage)> {
-        let client_handle = &mut self.client_handler;
-        let (id, text) = TgConnection::find_message_by_text(client_handle, &|msg| {
-            msg.starts_with(META_CONSTANT)
+    fn storage_id(&self) -> u64 {
+        self.storage_id.load(Ordering::SeqCst)
+    }
+
+    async fn create_file(
+        &self,
+        name: &str,
+        ino: u64,
+        parent: u64,
+        attr: &FileAttr,
+    ) -> FsResult<()> {
+        let _guard = self.meta_lock.lock().await;
+
+        let mut new_file_link = FileLink::new(name.to_string(), attr.clone(), None);
+        let (_, parent_link) = self.get_link(parent).await?;
+        compression::inherit(&parent_link, &mut new_file_link);
+
+        let attr_message = to_string(&new_file_link)?;
+        let attr_message_id = self.chat.send_message(attr_message, None).await?;
+
+        self.register_link(ino, attr_message_id).await?;
+
+        self.add_child(ino, name, &parent).await
+    }
+
+    async fn create_symlink(
+        &self,
+        name: &str,
+        ino: u64,
+        parent: u64,
+        attr: &FileAttr,
+        target: &str,
+    ) -> FsResult<()> {
+        let _guard = self.meta_lock.lock().await;
+
+        let link = FileLink::new(name.to_string(), attr.clone(), Some(target.to_string()));
+        let message_id = self.chat.send_message(to_string(&link)?, None).await?;
+
+        self.register_link(ino, message_id).await?;
+
+        self.add_child(ino, name, &parent).await
+    }
+
+    async fn create_dir(
+        &self,
+        name: &str,
+        ino: u64,
+        parent: Option<u64>,
+        attr: &FileAttr,
+    ) -> FsResult<()> {
+        let _guard = self.meta_lock.lock().await;
+
+        self.do_create_dir(name, ino, parent, attr).await
+    }
+
+    async fn set_attr(&self, ino: u64, attr: FileAttr) -> FsResult<()> {
+        let _guard = self.meta_lock.lock().await;
+
+        // The names are counted by `link` and `unlink`
+        self.update_file(ino, &|file: &mut FileLink| {
+            file.attr = FileAttr {
+                nlink: file.attr.nlink,
+                ..attr
+            }
+        })
+        .await
+    }
+
+    async fn set_xattr(&self, ino: u64, name: String, data: Vec<u8>) -> FsResult<()> {
+        let _guard = self.meta_lock.lock().await;
+
+        self.update_file(ino, &|file: &mut FileLink| {
+            file.xattr.insert(name.clone(), data.clone());
         })
-        .await?;
-        let info = utils::crop_letters(text.as_str(), META_CONSTANT.len());
-        let info: MetaMessage = from_str(info).ok()?;
-        Some((id, info))
+        .await
     }
 
-    async fn find_message_by_text(
-        client_handle: &mut ClientHandle,
-        filter: &dyn Fn(&str) -> bool,
-    ) -> Option<(i32, String)> {
-        let peer = TgConnection::get_peer();
+    async fn remove_xattr(&self, ino: u64, name: String) -> FsResult<()> {
+        let _guard = self.meta_lock.lock().await;
 
-        let mut messages = client_handle.search_messages(&peer);
+        self.update_file(ino, &|file: &mut FileLink| {
+            file.xattr.remove(name.as_str());
+        })
+        .await
+    }
 
-        while let Some(message) = messages.next().await.unwrap() {
-            if filter(message.text()) {
-                return Some((message.id(), message.text().to_string()));
+    async fn rename(
+        &self,
+        ino: u64,
+        name: &str,
+        new_name: &str,
+        parent: u64,
+        new_parent: u64,
+    ) -> FsResult<()> {
+        let _guard = self.meta_lock.lock().await;
+
+        let (_, directory) = self.get_link(new_parent).await?;
+        let existing = self
+            .get_entries(&directory)
+            .await?
+            .into_iter()
+            .find(|x| x.name == new_name)
+            .map(|x| x.ino);
+        match existing {
+            // Both names are links of the same inode, nothing changes
+            Some(data) if data == ino => return Ok(()),
+            Some(data) => {
+                self.do_unlink(data, new_parent, new_name).await?;
             }
+            None => {}
         }
 
-        None
+        let (_, link) = self.get_link(ino).await?;
+        self.remove_child(&link, name, &parent).await?;
+        self.add_child(ino, new_name, &new_parent).await
     }
 
-    fn get_peer() -> tl::enums::InputPeer {
-        let user_id: i32 = env!("TG_USER_ID").parse().expect("TG_USER_ID invalid");
-        let access_hash: i64 = env!("TG_ACCESS_HASH")
-            .parse()
-            .expect("TG_ACCESS_HASH invalid");
+    async fn link(&self, ino: u64, new_parent: u64, new_name: &str) -> FsResult<FileLink> {
+        let _guard = self.meta_lock.lock().await;
 
-        let peer = tl::types::InputPeerUser {
-            user_id,
-            access_hash,
+        let (message_id, mut link) = self.get_link(ino).await?;
+        link.attr.nlink += 1;
+        self.save_link(ino, message_id, &link).await?;
+
+        self.add_child(ino, new_name, &new_parent).await?;
+        link.name = new_name.to_string();
+        Ok(link)
+    }
+
+    async fn read_range(&self, ino: u64, offset: u64, size: u64) -> FsResult<Vec<u8>> {
+        let (_, link) = self.get_link(ino).await?;
+
+        let end = offset + size;
+        let mut data = vec![];
+        let mut chunk_start = 0;
+        for chunk in self.get_chunks(&link).await? {
+            let chunk_end = chunk_start + chunk.size;
+
+            // Download only the chunks that overlap with the range
+            if chunk_end > offset && chunk_start < end {
+                let from = offset.max(chunk_start) - chunk_start;
+                let to = end.min(chunk_end) - chunk_start;
+                let part = self.read_chunk(&chunk, from, to - from).await?;
+                data.extend(part);
+            }
+
+            chunk_start = chunk_end;
+        }
+        Ok(data)
+    }
+
+    async fn get_directory_page(
+        &self,
+        parent: &u64,
+        page: usize,
+    ) -> FsResult<Option<Vec<FileLink>>> {
+        let (_, directory) = self.get_link(*parent).await?;
+        let page_id = match directory.pages.get(page) {
+            Some(&data) => data,
+            None => return Ok(None),
         };
-        let peer_into = peer.into();
-        peer_into
+        let page = self.get_page(page_id).await?;
+        Ok(Some(self.get_entry_links(page.entries).await?))
+    }
+
+    /// All the pages are read at once, the inode table is read once for all the entries.
+    async fn get_directory_files(&self, parent: &u64) -> FsResult<Vec<FileLink>> {
+        let (_, directory) = self.get_link(*parent).await?;
+        let entries = self.get_entries(&directory).await?;
+        self.get_entry_links(entries).await
+    }
+
+    async fn get_file_attr(&self, ino: &u64) -> FsResult<FileLink> {
+        let (_, link) = self.get_link(*ino).await?;
+        Ok(link)
+    }
+
+    async fn write_to_file(&self, tempfile: &NamedTempFile, ino: u64) -> FsResult<()> {
+        let (_, link) = self.get_link(ino).await?;
+        let compress = compression::is_enabled(&link, self.compression);
+        let chunks = self.upload_chunks(tempfile.path(), compress).await?;
+
+        let _guard = self.meta_lock.lock().await;
+
+        let (message_id, mut result) = self.get_link(ino).await?;
+        result.attr.size = chunks.iter().map(|x| x.size).sum();
+        let old_chunks = self.get_chunks(&result).await?;
+        let old_pages = self.set_chunks(&mut result, chunks).await?;
+
+        self.save_link(ino, message_id, &result).await?;
+
+        if !old_pages.is_empty() {
+            self.chat.delete_messages(&old_pages).await?;
+        }
+        self.release_chunks(&old_chunks).await
+    }
+
+    async fn cleanup(&self) -> FsResult<()> {
+        let _guard = self.meta_lock.lock().await;
+
+        let meta_message = self.get_meta_message().await?;
+        if let Some((id, message)) = meta_message {
+            let shard_ids = self.get_all_shard_ids(&message).await?;
+            let mut messages_to_delete: Vec<i32> = self
+                .get_shards(&shard_ids)
+                .await?
+                .into_iter()
+                .flat_map(|x| x.files.into_iter().map(|(_, message_id)| message_id))
+                .collect();
+
+            let chunk_ids: Vec<i32> = self
+                .chat
+                .get_messages(&messages_to_delete)
+                .await?
+                .iter()
+                .filter_map(|x| x.as_ref().and_then(|t| from_str::<FileLink>(&t.text).ok()))
+                .flat_map(|x| {
+                    let chunk_ids = x.chunks.into_iter().map(|t| t.message_id);
+                    chunk_ids.chain(x.pages).chain(x.chunk_pages)
+                })
+                .collect();
+
+            messages_to_delete.extend(chunk_ids);
+            messages_to_delete.extend(shard_ids);
+            messages_to_delete.extend(message.index);
+            messages_to_delete.push(id);
+            // Shared chunks are listed by several files
+            messages_to_delete.sort();
+            messages_to_delete.dedup();
+            self.chat.delete_messages(&messages_to_delete).await?;
+            self.remember_meta_id(None)?;
+
+            self.content_index.lock().unwrap().clear();
+            if let Some(disk_cache) = &self.disk_cache {
+                disk_cache.save_content_index(message.id, &HashMap::new())?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_and_inc_ino(&self) -> FsResult<u64> {
+        let _guard = self.meta_lock.lock().await;
+
+        self.allocate_ino().await
+    }
+
+    async fn unlink(&self, ino: u64, parent: u64, name: &str) -> FsResult<u32> {
+        let _guard = self.meta_lock.lock().await;
+
+        self.do_unlink(ino, parent, name).await
+    }
+}
+
+/// Parse the meta message, `FsError::Unsupported` if it's stored in another format.
+fn parse_meta(text: &str) -> FsResult<MetaMessage> {
+    let stored: MetaVersion = from_prefixed_str(META_CONSTANT, text)?;
+    if stored.version != VERSION {
+        return Err(FsError::Unsupported(stored.version));
+    }
+    Ok(from_prefixed_str(META_CONSTANT, text)?)
+}
+
+/// Text of the message the chunk is attached to.
+fn chunk_text(hash: &str) -> String {
+    format!("{}\n{}", CHUNK_CONSTANT, hash)
+}
+
+/// md5 of the content of the file, as a hex string.
+fn file_hash(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut context = md5::Context::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let size = file.read(&mut buffer)?;
+        if size == 0 {
+            break;
+        }
+        context.consume(&buffer[..size]);
     }
+    Ok(format!("{:x}", context.compute()))
 }
@@ -0,0 +1,508 @@
+use std::io;
+use std::path::Path;
+
+use async_trait::async_trait;
+use grammers_client::ext::MessageMediaExt;
+use grammers_client::types::{DownloadIter, Message, MessageIter};
+use grammers_client::{Client, ClientHandle, InputMessage};
+use grammers_mtproto::mtp::RpcError;
+use grammers_mtsender::InvocationError;
+use grammers_tl_types as tl;
+
+use crate::chat::{Chat, ChatMessage, EditError};
+use crate::error::{FsError, FsResult};
+use crate::scheduler::RequestScheduler;
+use crate::types::FpfsInputFile;
+
+/// The biggest part telegram gives in one `upload.getFile` request.
+const DOWNLOAD_PART_SIZE: u64 = 512 * 1024;
+
+const MAX_MESSAGES_PER_REQUEST: usize = 100;
+
+/// Ids of channels and supergroups in the bot API format start with it.
+const CHANNEL_ID_PREFIX: &'static str = "-100";
+
+/// `Chat` backed by a real telegram chat.
+///
+/// `ClientHandle` is cheap to clone, so every request works with its own copy of the handle
+///   and requests may run in parallel. All the requests go through the scheduler.
+pub struct TgChat {
+    client_handler: ClientHandle,
+    peer: tl::enums::InputPeer,
+    /// Set if the chat is a channel or a supergroup, their messages are requested through it.
+    channel: Option<tl::enums::InputChannel>,
+    scheduler: RequestScheduler,
+}
+
+impl TgChat {
+    pub fn new(client_handler: ClientHandle, peer: tl::enums::InputPeer) -> TgChat {
+        let channel = match &peer {
+            tl::enums::InputPeer::Channel(data) => Some(
+                tl::types::InputChannel {
+                    channel_id: data.channel_id,
+                    access_hash: data.access_hash,
+                }
+                .into(),
+            ),
+            _ => None,
+        };
+
+        TgChat {
+            client_handler,
+            peer,
+            channel,
+            scheduler: RequestScheduler::new(0),
+        }
+    }
+
+    /// Send at most `budget` requests per minute, zero means no limit.
+    pub fn with_request_budget(mut self, budget: usize) -> TgChat {
+        self.scheduler = RequestScheduler::new(budget);
+        self
+    }
+
+    fn make_message(text: String, file: Option<FpfsInputFile>) -> InputMessage {
+        let message = InputMessage::text(text);
+        match file {
+            Some(data) => message.file(data.into()),
+            None => message,
+        }
+    }
+
+    fn send_message_request(
+        &self,
+        text: String,
+        random_id: i64,
+    ) -> tl::functions::messages::SendMessage {
+        tl::functions::messages::SendMessage {
+            no_webpage: true,
+            silent: true,
+            background: false,
+            clear_draft: false,
+            peer: self.peer.clone(),
+            reply_to_msg_id: None,
+            message: text,
+            random_id,
+            reply_markup: None,
+            entities: None,
+            schedule_date: None,
+        }
+    }
+
+    fn send_media_request(
+        &self,
+        text: String,
+        file: FpfsInputFile,
+        random_id: i64,
+    ) -> tl::functions::messages::SendMedia {
+        let media = tl::types::InputMediaUploadedDocument {
+            nosound_video: false,
+            force_file: true,
+            attributes: vec![tl::types::DocumentAttributeFilename {
+                file_name: file.name.clone(),
+            }
+            .into()],
+            file: file.into(),
+            thumb: None,
+            mime_type: "application/octet-stream".to_string(),
+            stickers: None,
+            ttl_seconds: None,
+        };
+
+        tl::functions::messages::SendMedia {
+            silent: true,
+            background: false,
+            clear_draft: false,
+            peer: self.peer.clone(),
+            reply_to_msg_id: None,
+            media: media.into(),
+            message: text,
+            random_id,
+            reply_markup: None,
+            entities: None,
+            schedule_date: None,
+        }
+    }
+
+    /// The next message of the search, the request is repeated if it fails.
+    async fn next_message(&self, messages: &mut MessageIter) -> FsResult<Option<Message>> {
+        let mut attempt = 0;
+        loop {
+            self.scheduler.acquire().await;
+            match messages.next().await {
+                Ok(data) => return Ok(data),
+                Err(e) => {
+                    attempt += 1;
+                    if !self.scheduler.should_retry(&e, attempt).await {
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+    }
+
+    /// The next part of the download, the request is repeated if it fails.
+    async fn next_part(&self, download_iter: &mut DownloadIter) -> FsResult<Option<Vec<u8>>> {
+        let mut attempt = 0;
+        loop {
+            self.scheduler.acquire().await;
+            match download_iter.next().await {
+                Ok(data) => return Ok(data),
+                Err(e) => {
+                    attempt += 1;
+                    if !self.scheduler.should_retry(&e, attempt).await {
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn file_location(&self, id: i32) -> FsResult<tl::enums::InputFileLocation> {
+        let file_message = self
+            .scheduler
+            .run(|| {
+                let mut client = self.client_handler.clone();
+                async move {
+                    client
+                        .get_messages_by_id(self.channel.as_ref(), &[id])
+                        .await
+                }
+            })
+            .await?
+            .into_iter()
+            .nth(0)
+            .flatten()
+            .ok_or(FsError::NotFound)?;
+
+        let media: Option<tl::enums::MessageMedia> = file_message.media();
+        media
+            .and_then(|x| x.to_input_file())
+            .ok_or(FsError::NotFound)
+    }
+}
+
+/// Id of the message sent with `random_id`, telegram reports it in the updates of the request.
+fn sent_message_id(updates: tl::enums::Updates, random_id: i64) -> Option<i32> {
+    let updates = match updates {
+        tl::enums::Updates::UpdateShortSentMessage(data) => return Some(data.id),
+        tl::enums::Updates::Updates(data) => data.updates,
+        tl::enums::Updates::Combined(data) => data.updates,
+        _ => return None,
+    };
+
+    updates.into_iter().find_map(|update| match update {
+        tl::enums::Update::MessageId(data) if data.random_id == random_id => Some(data.id),
+        _ => None,
+    })
+}
+
+/// Chat given by id, the ids of different kinds of chats may be the same.
+enum ChatId {
+    User(i32),
+    Group(i32),
+    Channel(i32),
+}
+
+/// Find the chat that keeps the filesystem, see `Config::chat`.
+///
+/// `me` is Saved Messages and `@username` is resolved by telegram. Chats given by id are looked up
+///   in the dialogs of the account, because requests to them need the access hash.
+pub async fn resolve_peer(client: &mut Client, chat: &str) -> Result<tl::enums::InputPeer, String> {
+    if chat == "me" {
+        return Ok(tl::enums::InputPeer::PeerSelf);
+    }
+
+    if chat.starts_with('@') {
+        return match client.resolve_username(&chat[1..]).await {
+            Ok(Some(found)) => Ok(found.to_input_peer()),
+            Ok(None) => Err(format!("Chat {} is not found", chat)),
+            Err(e) => Err(format!("Can't resolve {}: {}", chat, e)),
+        };
+    }
+
+    let id = parse_chat_id(chat)?;
+    let mut dialogs = client.iter_dialogs();
+    loop {
+        let dialog = match dialogs.next().await {
+            Ok(Some(data)) => data,
+            Ok(None) => break,
+            Err(e) => return Err(format!("Can't get the dialogs: {}", e)),
+        };
+
+        let peer = dialog.chat().to_input_peer();
+        let found = match (&id, &peer) {
+            (ChatId::User(id), tl::enums::InputPeer::User(data)) => data.user_id == *id,
+            (ChatId::Group(id), tl::enums::InputPeer::Chat(data)) => data.chat_id == *id,
+            (ChatId::Channel(id), tl::enums::InputPeer::Channel(data)) => data.channel_id == *id,
+            _ => false,
+        };
+        if found {
+            return Ok(peer);
+        }
+    }
+
+    Err(format!(
+        "Chat {} is not found, the account should be a member of it",
+        chat
+    ))
+}
+
+/// Ids are taken in the bot API format: `-100` and the id for channels and supergroups,
+///   `-` and the id for groups and just the id for users.
+fn parse_chat_id(chat: &str) -> Result<ChatId, String> {
+    let parse = |data: &str| -> Result<i32, String> {
+        data.parse().map_err(|_| format!("Invalid chat: {}", chat))
+    };
+
+    if chat.starts_with(CHANNEL_ID_PREFIX) {
+        Ok(ChatId::Channel(parse(&chat[CHANNEL_ID_PREFIX.len()..])?))
+    } else if chat.starts_with('-') {
+        Ok(ChatId::Group(parse(&chat[1..])?))
+    } else {
+        Ok(ChatId::User(parse(chat)?))
+    }
+}
+
+#[async_trait]
+impl Chat for TgChat {
+    async fn send_message(&self, text: String, file: Option<FpfsInputFile>) -> FsResult<i32> {
+        // Telegram sends the message only once for the same random id,
+        //   so the request may be repeated if the answer is lost
+        let random_id: i64 = rand::random();
+        let updates = match file {
+            Some(data) => {
+                let request = self.send_media_request(text, data, random_id);
+                self.scheduler
+                    .run(|| {
+                        let mut client = self.client_handler.clone();
+                        let request = &request;
+                        async move { client.invoke(request).await }
+                    })
+                    .await?
+            }
+            None => {
+                let request = self.send_message_request(text, random_id);
+                self.scheduler
+                    .run(|| {
+                        let mut client = self.client_handler.clone();
+                        let request = &request;
+                        async move { client.invoke(request).await }
+                    })
+                    .await?
+            }
+        };
+
+        sent_message_id(updates, random_id)
+            .ok_or_else(|| FsError::Rpc("The id of the sent message is not returned".to_string()))
+    }
+
+    async fn edit_message(
+        &self,
+        id: i32,
+        text: String,
+        file: Option<FpfsInputFile>,
+    ) -> Result<(), EditError> {
+        let result = self
+            .scheduler
+            .run(|| {
+                let mut client = self.client_handler.clone();
+                let message = TgChat::make_message(text.clone(), file.clone());
+                async move { client.edit_message(&self.peer, id, message).await }
+            })
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(InvocationError::Rpc(RpcError { name, .. })) if name == "MESSAGE_NOT_MODIFIED" => {
+                Ok(())
+            }
+            Err(InvocationError::Rpc(RpcError { name, .. }))
+                if name == "MESSAGE_EDIT_TIME_EXPIRED" =>
+            {
+                Err(EditError::TimeExpired)
+            }
+            Err(e) => Err(EditError::Failed(e.into())),
+        }
+    }
+
+    async fn delete_messages(&self, ids: &[i32]) -> FsResult<()> {
+        // Telegram deletes at most 100 messages per request
+        for batch in ids.chunks(MAX_MESSAGES_PER_REQUEST) {
+            self.scheduler
+                .run(|| {
+                    let mut client = self.client_handler.clone();
+                    async move { client.delete_messages(self.channel.as_ref(), batch).await }
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn get_messages(&self, ids: &[i32]) -> FsResult<Vec<Option<ChatMessage>>> {
+        let mut result = Vec::with_capacity(ids.len());
+
+        // Telegram returns at most 100 messages per request
+        for batch in ids.chunks(MAX_MESSAGES_PER_REQUEST) {
+            let messages = self
+                .scheduler
+                .run(|| {
+                    let mut client = self.client_handler.clone();
+                    async move {
+                        client
+                            .get_messages_by_id(self.channel.as_ref(), batch)
+                            .await
+                    }
+                })
+                .await?;
+
+            result.extend(messages.iter().map(|x| {
+                x.as_ref().map(|message| ChatMessage {
+                    id: message.id(),
+                    text: message.text().to_string(),
+                })
+            }));
+        }
+        Ok(result)
+    }
+
+    async fn find_message(
+        &self,
+        filter: &(dyn Fn(&str) -> bool + Sync),
+    ) -> FsResult<Option<ChatMessage>> {
+        let mut client = self.client_handler.clone();
+        let mut messages = client.search_messages(&self.peer);
+
+        while let Some(message) = self.next_message(&mut messages).await? {
+            if filter(message.text()) {
+                return Ok(Some(ChatMessage {
+                    id: message.id(),
+                    text: message.text().to_string(),
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn pin_message(&self, id: i32) -> FsResult<()> {
+        let request = tl::functions::messages::UpdatePinnedMessage {
+            silent: true,
+            unpin: false,
+            pm_oneside: false,
+            peer: self.peer.clone(),
+            id,
+        };
+        self.scheduler
+            .run(|| {
+                let mut client = self.client_handler.clone();
+                let request = &request;
+                async move { client.invoke(request).await }
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Only the newest pinned messages are looked through, as many as one request returns.
+    async fn find_pinned_message(
+        &self,
+        filter: &(dyn Fn(&str) -> bool + Sync),
+    ) -> FsResult<Option<ChatMessage>> {
+        let request = tl::functions::messages::Search {
+            peer: self.peer.clone(),
+            q: String::new(),
+            from_id: None,
+            top_msg_id: None,
+            filter: tl::enums::MessagesFilter::InputMessagesFilterPinned,
+            min_date: 0,
+            max_date: 0,
+            offset_id: 0,
+            add_offset: 0,
+            limit: MAX_MESSAGES_PER_REQUEST as i32,
+            max_id: 0,
+            min_id: 0,
+            hash: 0,
+        };
+        let found = self
+            .scheduler
+            .run(|| {
+                let mut client = self.client_handler.clone();
+                let request = &request;
+                async move { client.invoke(request).await }
+            })
+            .await?;
+
+        let messages = match found {
+            tl::enums::messages::Messages::Messages(data) => data.messages,
+            tl::enums::messages::Messages::Slice(data) => data.messages,
+            tl::enums::messages::Messages::ChannelMessages(data) => data.messages,
+            tl::enums::messages::Messages::NotModified(_) => vec![],
+        };
+        let message = messages.into_iter().find_map(|message| match message {
+            tl::enums::Message::Message(data) if filter(&data.message) => Some(ChatMessage {
+                id: data.id,
+                text: data.message,
+            }),
+            _ => None,
+        });
+        Ok(message)
+    }
+
+    async fn upload_file(&self, path: &Path) -> FsResult<FpfsInputFile> {
+        let path = path.to_str().ok_or_else(|| {
+            FsError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Not a UTF-8 path: {}", path.display()),
+            ))
+        })?;
+        let res: tl::enums::InputFile = self
+            .scheduler
+            .run(|| {
+                let mut client = self.client_handler.clone();
+                async move { client.upload_file(path).await }
+            })
+            .await?;
+        Ok(res.into())
+    }
+
+    async fn download_media(&self, id: i32) -> FsResult<Vec<u8>> {
+        let file_location = self.file_location(id).await?;
+
+        let mut client = self.client_handler.clone();
+        let mut download_iter = client.iter_download(file_location);
+        let mut file = vec![];
+        while let Some(part) = self.next_part(&mut download_iter).await? {
+            file.extend(part);
+        }
+
+        Ok(file)
+    }
+
+    async fn download_range(&self, id: i32, offset: u64, size: u64) -> FsResult<Vec<u8>> {
+        let file_location = self.file_location(id).await?;
+
+        // Telegram returns the file by parts aligned to the part size, so download all the parts
+        //   covering the range and cut the range out of them
+        let first_part = offset / DOWNLOAD_PART_SIZE;
+        let mut client = self.client_handler.clone();
+        let mut download_iter = client
+            .iter_download(file_location)
+            .chunk_size(DOWNLOAD_PART_SIZE as i32)
+            .skip_chunks(first_part as i32);
+
+        let start = (offset - first_part * DOWNLOAD_PART_SIZE) as usize;
+        let end = start + size as usize;
+
+        let mut data = vec![];
+        while data.len() < end {
+            match self.next_part(&mut download_iter).await? {
+                Some(part) => data.extend(part),
+                None => break,
+            }
+        }
+
+        let start = start.min(data.len());
+        let end = end.min(data.len());
+        Ok(data[start..end].to_vec())
+    }
+}
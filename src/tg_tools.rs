@@ -1,59 +1,41 @@
-use grammers_client::types::Message;
-use grammers_client::{ClientHandle, InputMessage};
-use grammers_mtproto::mtp::RpcError;
-use grammers_mtsender::InvocationError;
-use grammers_tl_types as tl;
+use crate::chat::{Chat, ChatMessage, EditError};
+use crate::error::{FsError, FsResult};
+use crate::types::FpfsInputFile;
 
-pub async fn resend_message(
+pub async fn resend_message<C: Chat>(
     old_message_id: i32,
-    message: InputMessage,
-    client_handler: &mut ClientHandle,
-    peer: &tl::enums::InputPeer,
-) -> i32 {
-    client_handler
-        .delete_messages(None, &[old_message_id])
-        .await
-        .unwrap();
-
-    // TODO this method should return message instance
-    client_handler.send_message(peer, message).await.unwrap();
-
-    last_message(client_handler, &peer).await
-}
-
-pub async fn last_message(client_handler: &mut ClientHandle, peer: &tl::enums::InputPeer) -> i32 {
-    let mut messages = client_handler.search_messages(&peer);
-    messages.next().await.unwrap().unwrap().id()
+    text: String,
+    file: Option<FpfsInputFile>,
+    chat: &C,
+) -> FsResult<i32> {
+    chat.delete_messages(&[old_message_id]).await?;
+    chat.send_message(text, file).await
 }
 
-pub async fn edit_or_recreate(
+/// Edit the message or send it again if it's too old to be edited.
+///
+/// Returns the id of the new message if the message was recreated.
+pub async fn edit_or_recreate<C: Chat>(
     id: i32,
-    message: InputMessage,
-    message_again: InputMessage,
-    client_handler: &mut ClientHandle,
-    peer: &tl::enums::InputPeer,
-) -> Option<i32> {
-    let result = client_handler.edit_message(&peer, id, message).await;
+    text: String,
+    file: Option<FpfsInputFile>,
+    chat: &C,
+) -> FsResult<Option<i32>> {
+    let result = chat.edit_message(id, text.clone(), file.clone()).await;
 
     match result {
-        Ok(_) => None,
-        Err(InvocationError::Rpc(RpcError { name, .. })) => {
-            if name == "MESSAGE_EDIT_TIME_EXPIRED" {
-                let res = resend_message(id, message_again, client_handler, peer).await;
-                Some(res)
-            } else {
-                None
-            }
+        Ok(_) => Ok(None),
+        Err(EditError::TimeExpired) => {
+            let res = resend_message(id, text, file, chat).await?;
+            Ok(Some(res))
         }
-        Err(e) => panic!(e),
+        Err(EditError::Failed(e)) => Err(e),
     }
 }
 
-pub async fn get_message(client_handle: &mut ClientHandle, file_id: i32) -> Message {
-    client_handle
-        .get_messages_by_id(None, &[file_id])
-        .await
-        .unwrap()
+pub async fn get_message<C: Chat>(chat: &C, file_id: i32) -> FsResult<ChatMessage> {
+    chat.get_messages(&[file_id])
+        .await?
         .remove(0)
-        .unwrap()
+        .ok_or(FsError::NotFound)
 }
@@ -6,46 +6,118 @@ use serde::{Deserialize, Serialize};
 
 use crate::external_serialization::FileAttrDef;
 
-pub const VERSION: &'static str = "v1";
+/// Format of the stored filesystem, the meta messages of other versions aren't read.
+pub const VERSION: &'static str = "v3";
+
+/// Only the version of the meta message, it's checked before the rest is parsed.
+#[derive(Deserialize)]
+pub struct MetaVersion {
+    #[serde(default)]
+    pub version: String,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct MetaMessage {
     pub version: String,
-    pub files: HashMap<u64, i32>,
+    /// Random id of the filesystem, the data saved between mounts is used only with the same one.
+    pub id: u64,
+    /// Ids of the `[INDEX]` messages with the ids of the shards of the inode table, in order.
+    pub index: Vec<i32>,
     pub next_ino: u64,
+    /// Random value changed by every edit, so concurrent edits of several mounts never produce
+    ///   the same text and the overwritten one is noticed.
+    pub stamp: u64,
+}
+
+/// Part of the list of the shards of the inode table.
+#[derive(Serialize, Deserialize)]
+pub struct MetaIndex {
+    /// Ids of the `[SHARD]` messages, in order.
+    pub shards: Vec<i32>,
+}
+
+/// Part of the inode table: inode -> id of the message with its `FileLink`.
+///   Chunks of the files are numbered as inodes too, they point to the messages with the content.
+#[derive(Serialize, Deserialize)]
+pub struct MetaShard {
+    pub files: HashMap<u64, i32>,
+    /// Amount of the file links pointing to the chunk, the chunk is removed when none is left.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub refs: HashMap<u64, u32>,
 }
 
+/// The inode itself: attributes and content. An inode may have several names (hard links),
+///   the names are kept in the directory pages.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FileLink {
+    /// The name the inode was created with, the listings replace it with the name
+    ///   of the directory entry.
     pub name: String,
-    pub children: Vec<u64>,
-    pub file: Option<FpfsInputFile>,
+    /// Ids of the `[DIR]` messages with the children of the directory.
+    pub pages: Vec<i32>,
+    /// Content of the file, in order. Long lists are kept in `chunk_pages` instead.
+    pub chunks: Vec<FileChunk>,
+    /// Ids of the `[CHUNKS]` messages with the content of the file, in order.
+    pub chunk_pages: Vec<i32>,
     pub xattr: HashMap<String, Vec<u8>>,
+    /// Path the symlink points to, `None` for other files.
+    pub target: Option<String>,
 
     #[serde(with = "FileAttrDef")]
     pub attr: FileAttr,
 }
 
 impl FileLink {
-    pub fn new_file(name: String, attr: FileAttr) -> FileLink {
+    /// A new empty inode of the kind in `attr`, `target` is the path a symlink points to.
+    pub fn new(name: String, attr: FileAttr, target: Option<String>) -> FileLink {
         FileLink {
             name,
-            children: vec![],
-            file: None,
+            pages: vec![],
+            chunks: vec![],
+            chunk_pages: vec![],
             xattr: HashMap::new(),
+            target,
             attr,
         }
     }
+}
 
-    pub fn new_dir(name: String, children: Vec<u64>, attr: FileAttr) -> FileLink {
-        FileLink {
-            name,
-            children,
-            file: None,
-            xattr: HashMap::new(),
-            attr,
-        }
-    }
+/// Part of the directory listing.
+#[derive(Serialize, Deserialize)]
+pub struct DirPage {
+    pub entries: Vec<DirEntry>,
+}
+
+/// A name of the inode in the directory.
+#[derive(Serialize, Deserialize)]
+pub struct DirEntry {
+    pub ino: u64,
+    pub name: String,
+}
+
+/// Part of the chunk list of a file.
+#[derive(Serialize, Deserialize)]
+pub struct ChunkPage {
+    pub chunks: Vec<FileChunk>,
+}
+
+/// Part of the file content stored as a media of a separate message.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileChunk {
+    /// Number of the chunk in the inode table, it keeps the reference count.
+    pub ino: u64,
+    pub message_id: i32,
+    pub size: u64,
+    /// Id of the uploaded file, the content is cached by it.
+    pub file_id: i64,
+    /// The uploaded file is compressed with zstd, `size` is the size before the compression.
+    pub compressed: bool,
+    /// Compressed sizes of the frames, see `compression::FRAME_SIZE`. Empty for the chunks
+    ///   compressed as one frame, they are downloaded whole.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub frames: Vec<u32>,
+    /// md5 of the content, the same content is stored once and shared by the files.
+    pub hash: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -54,6 +126,9 @@ pub struct FpfsInputFile {
     pub parts: i32,
     pub name: String,
     pub md5_checksum: String,
+    /// Files larger than 10MB are uploaded as big files which have no checksum.
+    #[serde(default)]
+    pub big: bool,
 }
 
 impl From<tl::enums::InputFile> for FpfsInputFile {
@@ -64,19 +139,34 @@ impl From<tl::enums::InputFile> for FpfsInputFile {
                 parts: data.parts,
                 name: data.name,
                 md5_checksum: data.md5_checksum,
+                big: false,
+            },
+            tl::enums::InputFile::Big(data) => FpfsInputFile {
+                id: data.id,
+                parts: data.parts,
+                name: data.name,
+                md5_checksum: String::new(),
+                big: true,
             },
-            _ => panic!("Panic"),
         }
     }
 }
 
 impl From<FpfsInputFile> for tl::enums::InputFile {
     fn from(data: FpfsInputFile) -> Self {
-        tl::enums::InputFile::File(tl::types::InputFile {
-            id: data.id,
-            parts: data.parts,
-            name: data.name,
-            md5_checksum: data.md5_checksum,
-        })
+        if data.big {
+            tl::enums::InputFile::Big(tl::types::InputFileBig {
+                id: data.id,
+                parts: data.parts,
+                name: data.name,
+            })
+        } else {
+            tl::enums::InputFile::File(tl::types::InputFile {
+                id: data.id,
+                parts: data.parts,
+                name: data.name,
+                md5_checksum: data.md5_checksum,
+            })
+        }
     }
 }
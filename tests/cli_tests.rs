@@ -0,0 +1,93 @@
+extern crate fpfs;
+
+use fpfs::{Cli, Command, MountOptions};
+
+fn parse(args: &[&str]) -> Result<Cli, String> {
+    let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+    Cli::parse(&args)
+}
+
+fn mount_options(cli: &Cli) -> (&str, &MountOptions) {
+    match &cli.command {
+        Command::Mount {
+            mountpoint,
+            options,
+        } => (mountpoint.as_str(), options),
+        _ => panic!("Not a mount command"),
+    }
+}
+
+#[test]
+fn mount_helper() {
+    let cli = parse(&[
+        "/sbin/mount.fpfs",
+        "/etc/fpfs.json",
+        "/mnt/telegram",
+        "-o",
+        "rw,ro,uid=1000,gid=100,allow_other,_netdev,noauto,cache_size=1000",
+    ])
+    .unwrap();
+
+    let (mountpoint, options) = mount_options(&cli);
+    assert_eq!("/mnt/telegram", mountpoint);
+    assert!(options.read_only);
+    assert!(options.allow_other);
+    assert!(!options.foreground);
+    assert_eq!(Some(1000), options.uid);
+    assert_eq!(Some(100), options.gid);
+
+    // The options meant for mount(8) are skipped, the others override the config
+    assert_eq!(
+        Some("/etc/fpfs.json"),
+        cli.flags.get("config").map(|x| x.as_str())
+    );
+    assert_eq!(
+        Some("1000"),
+        cli.flags.get("cache-size").map(|x| x.as_str())
+    );
+    assert!(!cli.flags.contains_key("-netdev"));
+    assert!(!cli.flags.contains_key("noauto"));
+}
+
+#[test]
+fn mount_helper_with_default_config() {
+    let cli = parse(&["mount.fpfs", "fpfs", "/mnt/telegram", "-n", "-o", "rw"]).unwrap();
+
+    let (mountpoint, options) = mount_options(&cli);
+    assert_eq!("/mnt/telegram", mountpoint);
+    assert!(!options.read_only);
+    assert!(!cli.flags.contains_key("config"));
+}
+
+#[test]
+fn mount_helper_errors() {
+    assert!(parse(&["mount.fpfs", "/mnt/telegram"]).is_err());
+    assert!(parse(&["mount.fpfs", "fpfs", "/mnt/telegram", "-o", "uid=me"]).is_err());
+    assert!(parse(&["mount.fpfs", "fpfs", "/mnt/telegram", "-o"]).is_err());
+}
+
+#[test]
+fn mount_command() {
+    let cli = parse(&[
+        "fpfs",
+        "mount",
+        "/mnt/telegram",
+        "--compress",
+        "-f",
+        "--chat",
+        "@storage",
+        "-o",
+        "config=fpfs_test.json",
+    ])
+    .unwrap();
+
+    let (mountpoint, options) = mount_options(&cli);
+    assert_eq!("/mnt/telegram", mountpoint);
+    assert!(options.compress);
+    assert!(options.foreground);
+    assert_eq!(Some("@storage"), cli.flags.get("chat").map(|x| x.as_str()));
+    assert_eq!(
+        Some("fpfs_test.json"),
+        cli.flags.get("config").map(|x| x.as_str())
+    );
+}
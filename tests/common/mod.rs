@@ -0,0 +1,200 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use tokio::runtime::Runtime;
+
+use fpfs::{Fpfs, MockChat, StorageBackend, TgConnection};
+
+/// Test content of `size` bytes, which doesn't repeat with the chunk sizes used in the tests.
+#[allow(dead_code)]
+pub fn content(size: usize) -> Vec<u8> {
+    (0..size).map(|x| (x % 251) as u8).collect()
+}
+
+/// Filesystem with a runtime of its own, the binary gives it the runtime of the telegram client.
+#[allow(dead_code)]
+pub fn filesystem<B: StorageBackend>(connection: B) -> Fpfs<B> {
+    Fpfs::with_runtime(connection, Arc::new(Runtime::new().unwrap()))
+}
+
+/// Filesystem over a mock chat saved in `directory`, so it can be mounted again.
+#[allow(dead_code)]
+pub fn mock_filesystem<P: AsRef<Path>>(directory: P) -> Fpfs<TgConnection<MockChat>> {
+    filesystem(TgConnection::with_chat(MockChat::in_dir(directory)))
+}
+
+/// Mount the filesystem into a temporary directory and run the common scenario against it.
+pub fn check_filesystem<B: StorageBackend + 'static>(filesystem: Fpfs<B>) {
+    with_mounted(filesystem, |path| {
+        let file_list = fs::read_dir(path)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.unwrap().path())
+            .collect::<Vec<PathBuf>>();
+
+        assert!(file_list.is_empty());
+
+        file_loop(path, "another", 0, "123");
+
+        let dir_path = format!("{}/{}", path.as_os_str().to_str().unwrap(), "another");
+        let another_dir_path = format!("{}/{}", path.as_os_str().to_str().unwrap(), "another2");
+        rename_loop(path, &dir_path.as_str(), &another_dir_path.as_str());
+
+        file_loop(path, "another_one_file", 1, "456");
+
+        let dir_path = format!("{}/{}", path.as_os_str().to_str().unwrap(), "my_dir");
+        fs::create_dir(dir_path).unwrap();
+
+        file_loop_with_dir(path, "my_dir", "another", 0, "123");
+
+        remove_loop(path, "another_one_file", 3);
+
+        let dir_path = format!("{}/{}", path.as_os_str().to_str().unwrap(), "my_dir");
+        let another_dir_path = format!(
+            "{}/{}",
+            path.as_os_str().to_str().unwrap(),
+            "my_another_dir"
+        );
+        rename_loop(path, &dir_path.as_str(), &another_dir_path.as_str());
+
+        remove_dir_loop(path, "my_another_dir", 2);
+    });
+}
+
+/// Mount the filesystem into a temporary directory, run `action` with the mount path and unmount it.
+pub fn with_mounted<B: StorageBackend + 'static>(filesystem: Fpfs<B>, action: impl FnOnce(&Path)) {
+    let tmpfile = tempfile::tempdir().unwrap();
+
+    let options = ["-f", "-o", "fsname=fpfs"]
+        .iter()
+        .map(|o| o.as_ref())
+        .collect::<Vec<&OsStr>>();
+
+    let session = unsafe { fuse::spawn_mount(filesystem, &tmpfile, &options).unwrap() };
+
+    sleep(Duration::from_secs(1));
+
+    let path = tmpfile.path();
+
+    action(path);
+
+    Command::new("umount")
+        .arg(path.to_str().unwrap())
+        .spawn()
+        .unwrap();
+
+    std::mem::drop(session);
+}
+
+fn rename_loop(path: &Path, dir_path: &str, another_path: &str) {
+    let before_size = fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|x| x.unwrap().path())
+        .collect::<Vec<PathBuf>>()
+        .len();
+
+    fs::rename(dir_path, another_path).unwrap();
+
+    let file_list = fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|x| x.unwrap().path())
+        .collect::<Vec<PathBuf>>();
+
+    assert_eq!(before_size, file_list.len());
+
+    assert!(file_list
+        .iter()
+        .map(|x| x.to_str().unwrap())
+        .any(|x| x == another_path));
+}
+
+fn file_loop(path: &Path, file_name: &str, amount_of_existing_files: usize, content: &str) {
+    let another_path = format!("{}/{}", path.as_os_str().to_str().unwrap(), file_name);
+    File::create(&another_path).unwrap();
+
+    let file_list = fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|x| x.unwrap().path())
+        .collect::<Vec<PathBuf>>();
+
+    assert_eq!(file_list.len(), amount_of_existing_files + 1);
+    assert!(file_list
+        .iter()
+        .map(|x| x.to_str().unwrap())
+        .any(|x| x == another_path));
+
+    fs::write(&another_path, content).unwrap();
+
+    let bytes = fs::read(&another_path).unwrap();
+    let result = String::from_utf8(bytes).unwrap();
+
+    assert_eq!(content, result);
+}
+
+fn remove_loop(path: &Path, file_name: &str, amount_of_existing_files: usize) {
+    let another_path = format!("{}/{}", path.as_os_str().to_str().unwrap(), file_name);
+
+    fs::remove_file(&another_path).unwrap();
+
+    let file_list = fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|x| x.unwrap().path())
+        .collect::<Vec<PathBuf>>();
+
+    assert_eq!(file_list.len(), amount_of_existing_files - 1);
+}
+
+fn remove_dir_loop(path: &Path, dir_name: &str, amount_of_existing_files: usize) {
+    let another_path = format!("{}/{}", path.as_os_str().to_str().unwrap(), dir_name);
+
+    fs::remove_dir_all(&another_path).unwrap();
+
+    let file_list = fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|x| x.unwrap().path())
+        .collect::<Vec<PathBuf>>();
+
+    assert_eq!(file_list.len(), amount_of_existing_files - 1);
+}
+
+fn file_loop_with_dir(
+    path: &Path,
+    dir: &str,
+    file_name: &str,
+    amount_of_existing_files: usize,
+    content: &str,
+) {
+    let goal_dir = format!("{}/{}", path.as_os_str().to_str().unwrap(), dir);
+    let another_path = format!("{}/{}", &goal_dir, file_name);
+    File::create(&another_path).unwrap();
+
+    let file_list = fs::read_dir(goal_dir)
+        .unwrap()
+        .into_iter()
+        .map(|x| x.unwrap().path())
+        .collect::<Vec<PathBuf>>();
+
+    assert_eq!(file_list.len(), amount_of_existing_files + 1);
+    assert!(file_list
+        .iter()
+        .map(|x| x.to_str().unwrap())
+        .any(|x| x == another_path));
+
+    fs::write(&another_path, content).unwrap();
+
+    let bytes = fs::read(&another_path).unwrap();
+    let result = String::from_utf8(bytes).unwrap();
+
+    assert_eq!(content, result);
+}
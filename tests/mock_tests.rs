@@ -0,0 +1,974 @@
+extern crate fpfs;
+
+use std::ffi::CString;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use simple_logger::SimpleLogger;
+use tokio::runtime::Runtime;
+
+use fpfs::{Chat, DiskCache, EncryptedChat, FsError, MockChat, TgConnection};
+
+mod common;
+
+#[test]
+fn create_empty_file_in_memory() {
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Debug)
+        .init()
+        .unwrap();
+
+    let chat = MockChat::in_memory().with_edit_window(Duration::from_secs(0));
+    let filesystem = common::filesystem(TgConnection::with_chat(chat));
+
+    common::check_filesystem(filesystem);
+}
+
+#[test]
+fn create_empty_file_in_dir() {
+    let directory = tempfile::tempdir().unwrap();
+
+    let filesystem = common::mock_filesystem(&directory);
+
+    common::check_filesystem(filesystem);
+}
+
+#[test]
+fn file_larger_than_chunk() {
+    let connection = TgConnection::with_chat(MockChat::in_memory()).with_chunk_size(1000);
+
+    common::with_mounted(common::filesystem(connection), |path| {
+        let file_path = path.join("big_file");
+        let content = common::content(4000);
+
+        fs::write(&file_path, &content).unwrap();
+
+        assert_eq!(content, fs::read(&file_path).unwrap());
+    });
+}
+
+#[test]
+fn write_at_offset() {
+    let filesystem = common::filesystem(TgConnection::with_chat(MockChat::in_memory()));
+
+    common::with_mounted(filesystem, |path| {
+        let file_path = path.join("file");
+        fs::write(&file_path, "hello").unwrap();
+
+        let mut file = OpenOptions::new().append(true).open(&file_path).unwrap();
+        file.write_all(b" world").unwrap();
+        assert_eq!("hello world", fs::read_to_string(&file_path).unwrap());
+
+        let mut file = OpenOptions::new().write(true).open(&file_path).unwrap();
+        file.seek(SeekFrom::Start(1)).unwrap();
+        file.write_all(b"ELL").unwrap();
+        assert_eq!("hELLo world", fs::read_to_string(&file_path).unwrap());
+
+        fs::write(&file_path, "bye").unwrap();
+        assert_eq!("bye", fs::read_to_string(&file_path).unwrap());
+    });
+}
+
+#[test]
+fn read_range() {
+    let connection = TgConnection::with_chat(MockChat::in_memory()).with_chunk_size(1000);
+
+    common::with_mounted(common::filesystem(connection), |path| {
+        let file_path = path.join("file");
+        let content = common::content(4000);
+        fs::write(&file_path, &content).unwrap();
+
+        let mut file = File::open(&file_path).unwrap();
+        let mut buffer = vec![0u8; 1000];
+        file.seek(SeekFrom::Start(1500)).unwrap();
+        file.read_exact(&mut buffer).unwrap();
+        assert_eq!(&content[1500..2500], buffer.as_slice());
+
+        file.seek(SeekFrom::Start(5000)).unwrap();
+        assert_eq!(0, file.read(&mut buffer).unwrap());
+    });
+}
+
+#[test]
+fn write_in_many_calls() {
+    let directory = tempfile::tempdir().unwrap();
+    let uploads = || {
+        fs::read_dir(directory.path().join("uploads"))
+            .unwrap()
+            .count()
+    };
+
+    let chat = MockChat::in_dir(&directory);
+    let requests = chat.request_counter();
+    let downloaded = chat.download_counter();
+    let connection = TgConnection::with_chat(chat).with_chunk_size(64 * 1024);
+
+    common::with_mounted(common::filesystem(connection), |path| {
+        let file_path = path.join("file");
+        let content = common::content(300_000);
+
+        // The writes go to the local copy, it's uploaded once when the file is closed
+        let mut file = File::create(&file_path).unwrap();
+        let before = requests.load(Ordering::SeqCst);
+        for part in content.chunks(4096) {
+            file.write_all(part).unwrap();
+        }
+        assert_eq!(before, requests.load(Ordering::SeqCst));
+        drop(file);
+        assert_eq!(5, uploads());
+
+        // Opening with `O_TRUNC` doesn't download the old content
+        fs::write(&file_path, &content).unwrap();
+        assert_eq!(0, downloaded.load(Ordering::SeqCst));
+        assert_eq!(10, uploads());
+
+        assert_eq!(
+            content.len() as u64,
+            fs::metadata(&file_path).unwrap().len()
+        );
+        assert_eq!(content, fs::read(&file_path).unwrap());
+    });
+}
+
+#[test]
+fn many_files() {
+    let filesystem = common::filesystem(TgConnection::with_chat(MockChat::in_memory()));
+
+    common::with_mounted(filesystem, |path| {
+        for i in 0..600 {
+            File::create(path.join(format!("file_{}", i))).unwrap();
+        }
+
+        assert_eq!(600, fs::read_dir(path).unwrap().count());
+    });
+}
+
+#[test]
+fn big_directory() {
+    let directory = tempfile::tempdir().unwrap();
+
+    common::with_mounted(common::mock_filesystem(&directory), |path| {
+        let dir_path = path.join("dir");
+        fs::create_dir(&dir_path).unwrap();
+
+        for i in 0..1500 {
+            File::create(dir_path.join(format!("file_{}", i))).unwrap();
+        }
+        assert_eq!(1500, fs::read_dir(&dir_path).unwrap().count());
+
+        for i in 0..1000 {
+            fs::remove_file(dir_path.join(format!("file_{}", i))).unwrap();
+        }
+        assert_eq!(500, fs::read_dir(&dir_path).unwrap().count());
+        assert!(dir_path.join("file_1200").exists());
+    });
+
+    // The listing reads the directory and the inode table once, not for every page
+    let chat = MockChat::in_dir(&directory);
+    let requests = chat.request_counter();
+    common::with_mounted(common::filesystem(TgConnection::with_chat(chat)), |path| {
+        let dir_path = path.join("dir");
+        assert!(dir_path.is_dir());
+
+        let before = requests.load(Ordering::SeqCst);
+        assert_eq!(500, fs::read_dir(&dir_path).unwrap().count());
+        assert!(requests.load(Ordering::SeqCst) - before < 20);
+    });
+}
+
+#[test]
+fn parallel_writes() {
+    let filesystem = common::filesystem(TgConnection::with_chat(MockChat::in_memory()));
+
+    common::with_mounted(filesystem, |path| {
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let file_path = path.join(format!("file_{}", i));
+                thread::spawn(move || {
+                    let content = format!("content of file {}", i).repeat(1000);
+                    fs::write(&file_path, &content).unwrap();
+                    assert_eq!(content, fs::read_to_string(&file_path).unwrap());
+                })
+            })
+            .collect();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(8, fs::read_dir(path).unwrap().count());
+    });
+}
+
+#[test]
+fn directories_are_listed_once() {
+    let directory = tempfile::tempdir().unwrap();
+
+    let filesystem = common::mock_filesystem(&directory);
+    common::with_mounted(filesystem, |path| {
+        for i in 0..10 {
+            let dir_path = path.join(format!("dir_{}", i));
+            fs::create_dir(&dir_path).unwrap();
+            for j in 0..100 {
+                File::create(dir_path.join(format!("file_{}", j))).unwrap();
+            }
+        }
+    });
+
+    // Mount again, so nothing is cached yet
+    let chat = MockChat::in_dir(&directory);
+    let requests = chat.request_counter();
+    let filesystem = common::filesystem(TgConnection::with_chat(chat));
+    common::with_mounted(filesystem, |path| {
+        let mut files = 0;
+        for dir in fs::read_dir(path).unwrap() {
+            for file in fs::read_dir(dir.unwrap().path()).unwrap() {
+                fs::metadata(file.unwrap().path()).unwrap();
+                files += 1;
+            }
+        }
+        assert_eq!(1000, files);
+
+        // A few requests for every directory, not for every file
+        assert!(requests.load(Ordering::SeqCst) < 250);
+    });
+}
+
+#[test]
+fn cache_between_mounts() {
+    let directory = tempfile::tempdir().unwrap();
+    let cache_directory = tempfile::tempdir().unwrap();
+    let content = common::content(4000);
+
+    let disk_cache = Arc::new(DiskCache::open(&cache_directory, 1024 * 1024).unwrap());
+    let connection =
+        TgConnection::with_chat(MockChat::in_dir(&directory)).with_disk_cache(disk_cache.clone());
+    let filesystem = common::filesystem(connection).with_disk_cache(disk_cache);
+    common::with_mounted(filesystem, |path| {
+        fs::create_dir(path.join("dir")).unwrap();
+        fs::write(path.join("dir").join("file"), &content).unwrap();
+    });
+
+    // Another device changes the directory meanwhile
+    common::with_mounted(common::mock_filesystem(&directory), |path| {
+        fs::write(path.join("dir").join("other"), "hello").unwrap();
+    });
+
+    let chat = MockChat::in_dir(&directory);
+    let downloaded = chat.download_counter();
+    let disk_cache = Arc::new(DiskCache::open(&cache_directory, 1024 * 1024).unwrap());
+    let connection = TgConnection::with_chat(chat).with_disk_cache(disk_cache.clone());
+    let filesystem = common::filesystem(connection).with_disk_cache(disk_cache);
+    common::with_mounted(filesystem, |path| {
+        // The restored listing is read again, the content is taken from the cache
+        assert_eq!(2, fs::read_dir(path.join("dir")).unwrap().count());
+        assert_eq!(content, fs::read(path.join("dir").join("file")).unwrap());
+        assert_eq!(0, downloaded.load(Ordering::SeqCst));
+    });
+}
+
+#[test]
+fn cache_of_another_chat_is_not_used() {
+    let directory = tempfile::tempdir().unwrap();
+    let other_directory = tempfile::tempdir().unwrap();
+    let cache_directory = tempfile::tempdir().unwrap();
+
+    let mount = |chat_directory: &Path| {
+        let disk_cache = Arc::new(DiskCache::open(&cache_directory, 1024 * 1024).unwrap());
+        let connection = TgConnection::with_chat(MockChat::in_dir(chat_directory))
+            .with_disk_cache(disk_cache.clone());
+        common::filesystem(connection).with_disk_cache(disk_cache)
+    };
+
+    common::with_mounted(mount(directory.path()), |path| {
+        fs::write(path.join("file"), "hello").unwrap();
+    });
+
+    common::with_mounted(mount(other_directory.path()), |path| {
+        assert_eq!(0, fs::read_dir(path).unwrap().count());
+        assert!(!path.join("file").exists());
+    });
+}
+
+#[test]
+fn chunks_of_another_chat_are_not_reused() {
+    let directory = tempfile::tempdir().unwrap();
+    let other_directory = tempfile::tempdir().unwrap();
+    let cache_directory = tempfile::tempdir().unwrap();
+    let uploads = |chat_directory: &Path| {
+        fs::read_dir(chat_directory.join("uploads"))
+            .unwrap()
+            .count()
+    };
+
+    let mount = |chat_directory: &Path| {
+        let disk_cache = Arc::new(DiskCache::open(&cache_directory, 1024 * 1024).unwrap());
+        let connection = TgConnection::with_chat(MockChat::in_dir(chat_directory))
+            .with_disk_cache(disk_cache.clone());
+        common::filesystem(connection).with_disk_cache(disk_cache)
+    };
+
+    // Both chats have the same message ids, the chunks are known by the cache of the first one
+    common::with_mounted(mount(directory.path()), |path| {
+        fs::write(path.join("first"), "shared content").unwrap();
+        fs::write(path.join("second"), "other content").unwrap();
+    });
+    common::with_mounted(mount(other_directory.path()), |path| {
+        fs::write(path.join("second"), "other content").unwrap();
+        fs::write(path.join("first"), "shared content").unwrap();
+    });
+    assert_eq!(2, uploads(other_directory.path()));
+
+    // Without the cache the content comes from the chats only
+    for chat_directory in &[directory.path(), other_directory.path()] {
+        common::with_mounted(common::mock_filesystem(chat_directory), |path| {
+            assert_eq!(
+                "shared content",
+                fs::read_to_string(path.join("first")).unwrap()
+            );
+            assert_eq!(
+                "other content",
+                fs::read_to_string(path.join("second")).unwrap()
+            );
+        });
+    }
+}
+
+#[test]
+fn lost_content_fails_only_the_read() {
+    let directory = tempfile::tempdir().unwrap();
+
+    let filesystem = common::mock_filesystem(&directory);
+    common::with_mounted(filesystem, |path| {
+        fs::write(path.join("file"), "hello").unwrap();
+    });
+
+    // The attached file of the chunk is gone, as if the message was deleted by hand
+    for entry in fs::read_dir(directory.path().join("uploads")).unwrap() {
+        fs::remove_file(entry.unwrap().path()).unwrap();
+    }
+
+    let filesystem = common::mock_filesystem(&directory);
+    common::with_mounted(filesystem, |path| {
+        assert!(fs::read(path.join("file")).is_err());
+
+        // The filesystem keeps working
+        fs::write(path.join("other"), "world").unwrap();
+        assert_eq!("world", fs::read_to_string(path.join("other")).unwrap());
+        assert_eq!(2, fs::read_dir(path).unwrap().count());
+    });
+}
+
+#[test]
+fn failed_upload_is_kept_after_release() {
+    let directory = tempfile::tempdir().unwrap();
+    let content = common::content(5000);
+
+    let chat = MockChat::in_dir(&directory);
+    let failing_uploads = chat.failing_uploads();
+    common::with_mounted(common::filesystem(TgConnection::with_chat(chat)), |path| {
+        // Both the flush on close and the release fail
+        failing_uploads.store(2, Ordering::SeqCst);
+        let mut file = File::create(path.join("file")).unwrap();
+        file.write_all(&content).unwrap();
+        drop(file);
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(0, failing_uploads.load(Ordering::SeqCst));
+
+        // The next handle continues with the changes and uploads them when it's closed
+        assert_eq!(content, fs::read(path.join("file")).unwrap());
+    });
+
+    let filesystem = common::mock_filesystem(&directory);
+    common::with_mounted(filesystem, |path| {
+        assert_eq!(content, fs::read(path.join("file")).unwrap());
+    });
+}
+
+#[test]
+fn pinned_messages_are_found_without_search() {
+    let runtime = Runtime::new().unwrap();
+    let is_meta = |text: &str| text.starts_with("[META]");
+
+    // A mount without the cache, e.g. on another device
+    let plain = tempfile::tempdir().unwrap();
+    common::with_mounted(common::mock_filesystem(&plain), |path| {
+        fs::write(path.join("file"), "hello").unwrap();
+    });
+    let chat = MockChat::in_dir(&plain);
+    let searches = chat.search_counter();
+    let chat = runtime
+        .block_on(EncryptedChat::open_plain(chat, None, &is_meta))
+        .unwrap();
+    common::with_mounted(common::filesystem(TgConnection::with_chat(chat)), |path| {
+        assert_eq!("hello", fs::read_to_string(path.join("file")).unwrap());
+    });
+    assert_eq!(0, searches.load(Ordering::SeqCst));
+
+    let encrypted = tempfile::tempdir().unwrap();
+    let unlock = EncryptedChat::unlock(MockChat::in_dir(&encrypted), b"secret", None);
+    let (chat, _) = runtime.block_on(unlock).unwrap();
+    common::with_mounted(common::filesystem(TgConnection::with_chat(chat)), |path| {
+        fs::write(path.join("file"), "hello").unwrap();
+    });
+    let chat = MockChat::in_dir(&encrypted);
+    let searches = chat.search_counter();
+    let open = EncryptedChat::open_plain(chat, None, &is_meta);
+    assert!(matches!(runtime.block_on(open), Err(FsError::KeyRequired)));
+    let chat = MockChat::in_dir(&encrypted);
+    let searches_of_unlock = chat.search_counter();
+    let (chat, _) = runtime
+        .block_on(EncryptedChat::unlock(chat, b"secret", None))
+        .unwrap();
+    common::with_mounted(common::filesystem(TgConnection::with_chat(chat)), |path| {
+        assert_eq!("hello", fs::read_to_string(path.join("file")).unwrap());
+    });
+    assert_eq!(0, searches.load(Ordering::SeqCst));
+    assert_eq!(0, searches_of_unlock.load(Ordering::SeqCst));
+}
+
+#[test]
+fn encrypted_filesystem() {
+    let directory = tempfile::tempdir().unwrap();
+    let runtime = Runtime::new().unwrap();
+    let content = common::content(200_000);
+
+    let unlock = EncryptedChat::unlock(MockChat::in_dir(&directory), b"secret", None);
+    let (chat, _) = runtime.block_on(unlock).unwrap();
+    common::with_mounted(common::filesystem(TgConnection::with_chat(chat)), |path| {
+        fs::write(path.join("private_name"), &content).unwrap();
+    });
+
+    // Neither the names nor the content are stored as is
+    let messages = fs::read_to_string(directory.path().join("messages.json")).unwrap();
+    assert!(!messages.contains("private_name"));
+    for entry in fs::read_dir(directory.path().join("uploads")).unwrap() {
+        let data = fs::read(entry.unwrap().path()).unwrap();
+        assert!(!data.windows(1000).any(|x| x == &content[..1000]));
+    }
+
+    let unlock = EncryptedChat::unlock(MockChat::in_dir(&directory), b"wrong", None);
+    assert!(matches!(runtime.block_on(unlock), Err(FsError::WrongKey)));
+
+    // Without the key the filesystem isn't taken for a missing one
+    let is_meta = |text: &str| text.starts_with("[META]");
+    for known_meta in vec![None, Some(1), Some(2)] {
+        let open = EncryptedChat::open_plain(MockChat::in_dir(&directory), known_meta, &is_meta);
+        assert!(matches!(runtime.block_on(open), Err(FsError::KeyRequired)));
+    }
+    let open = EncryptedChat::open_plain(MockChat::in_memory(), None, &is_meta);
+    assert!(runtime.block_on(open).is_ok());
+
+    // A plain message that isn't the meta message doesn't hide the key record
+    let chat = Arc::new(MockChat::in_memory());
+    let plain = runtime.block_on(chat.send_message("hello".to_string(), None));
+    let unlock = EncryptedChat::unlock(chat.clone(), b"secret", None);
+    runtime.block_on(unlock).unwrap();
+    let open = EncryptedChat::open_plain(chat, Some(plain.unwrap()), &is_meta);
+    assert!(matches!(runtime.block_on(open), Err(FsError::KeyRequired)));
+
+    let unlock = EncryptedChat::unlock(MockChat::in_dir(&directory), b"secret", None);
+    let (chat, _) = runtime.block_on(unlock).unwrap();
+    common::with_mounted(common::filesystem(TgConnection::with_chat(chat)), |path| {
+        assert_eq!(content, fs::read(path.join("private_name")).unwrap());
+
+        // A range in the middle of the file, not in the first encrypted block
+        let mut file = File::open(path.join("private_name")).unwrap();
+        file.seek(SeekFrom::Start(150_000)).unwrap();
+        let mut data = vec![0u8; 1000];
+        file.read_exact(&mut data).unwrap();
+        assert_eq!(&content[150_000..151_000], &data[..]);
+    });
+}
+
+#[test]
+fn cut_encrypted_file() {
+    let directory = tempfile::tempdir().unwrap();
+    let runtime = Runtime::new().unwrap();
+    // Two full blocks, so the last block is empty
+    let content = common::content(2 * 65536);
+
+    let unlock = EncryptedChat::unlock(MockChat::in_dir(&directory), b"secret", None);
+    let (chat, _) = runtime.block_on(unlock).unwrap();
+    common::with_mounted(common::filesystem(TgConnection::with_chat(chat)), |path| {
+        fs::write(path.join("file"), &content).unwrap();
+    });
+
+    // The nonce prefix and the blocks with their tags
+    let upload = fs::read_dir(directory.path().join("uploads"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let block = 65536 + 16;
+    assert_eq!(15 + 2 * block + 16, fs::metadata(&upload).unwrap().len());
+
+    // The file is cut where a block ends
+    for size in vec![15 + 2 * block, 15 + block] {
+        let file = OpenOptions::new().write(true).open(&upload).unwrap();
+        file.set_len(size).unwrap();
+
+        let unlock = EncryptedChat::unlock(MockChat::in_dir(&directory), b"secret", None);
+        let (chat, _) = runtime.block_on(unlock).unwrap();
+        common::with_mounted(common::filesystem(TgConnection::with_chat(chat)), |path| {
+            assert!(fs::read(path.join("file")).is_err());
+        });
+    }
+}
+
+#[test]
+fn long_names_in_encrypted_directory() {
+    let directory = tempfile::tempdir().unwrap();
+    let runtime = Runtime::new().unwrap();
+    // 240 bytes each, the encrypted page is much longer than its text
+    let names: Vec<String> = (0..60)
+        .map(|i| format!("{:02}{}", i, "文件".repeat(39)))
+        .collect();
+
+    let unlock = EncryptedChat::unlock(MockChat::in_dir(&directory), b"secret", None);
+    let (chat, _) = runtime.block_on(unlock).unwrap();
+    common::with_mounted(common::filesystem(TgConnection::with_chat(chat)), |path| {
+        for name in &names {
+            fs::write(path.join(name), name).unwrap();
+        }
+        assert_eq!(names.len(), fs::read_dir(path).unwrap().count());
+    });
+
+    let unlock = EncryptedChat::unlock(MockChat::in_dir(&directory), b"secret", None);
+    let (chat, _) = runtime.block_on(unlock).unwrap();
+    common::with_mounted(common::filesystem(TgConnection::with_chat(chat)), |path| {
+        assert_eq!(names.len(), fs::read_dir(path).unwrap().count());
+        for name in &names {
+            assert_eq!(*name, fs::read_to_string(path.join(name)).unwrap());
+        }
+    });
+}
+
+#[test]
+fn compressed_files() {
+    let directory = tempfile::tempdir().unwrap();
+    let text = "2021-01-01 12:00:00 INFO Request handled\n".repeat(20_000);
+    let random: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+    let uploaded_size = || -> u64 {
+        fs::read_dir(directory.path().join("uploads"))
+            .unwrap()
+            .map(|x| x.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+
+    let connection = TgConnection::with_chat(MockChat::in_dir(&directory)).with_compression(true);
+    common::with_mounted(common::filesystem(connection), |path| {
+        fs::write(path.join("log"), &text).unwrap();
+        assert!(uploaded_size() < text.len() as u64 / 10);
+
+        // Random data doesn't compress, so it's stored as is
+        let before = uploaded_size();
+        fs::write(path.join("random"), &random).unwrap();
+        assert_eq!(random.len() as u64, uploaded_size() - before);
+
+        // The directory turns the compression off for its files
+        fs::create_dir(path.join("raw")).unwrap();
+        let dir = CString::new(path.join("raw").to_str().unwrap()).unwrap();
+        let name = CString::new("user.fpfs.compress").unwrap();
+        let result = unsafe {
+            libc::setxattr(
+                dir.as_ptr(),
+                name.as_ptr(),
+                b"off".as_ptr() as *const _,
+                3,
+                0,
+            )
+        };
+        assert_eq!(0, result);
+
+        let before = uploaded_size();
+        fs::write(path.join("raw").join("log"), &text).unwrap();
+        assert_eq!(text.len() as u64, uploaded_size() - before);
+    });
+
+    let filesystem = common::mock_filesystem(&directory);
+    common::with_mounted(filesystem, |path| {
+        assert_eq!(text, fs::read_to_string(path.join("log")).unwrap());
+        assert_eq!(random, fs::read(path.join("random")).unwrap());
+        assert_eq!(
+            text,
+            fs::read_to_string(path.join("raw").join("log")).unwrap()
+        );
+    });
+}
+
+#[test]
+fn compressed_range_reads() {
+    let directory = tempfile::tempdir().unwrap();
+    let text: String = (0..200_000)
+        .map(|x| format!("{} INFO Request handled\n", x))
+        .collect();
+
+    let connection = TgConnection::with_chat(MockChat::in_dir(&directory)).with_compression(true);
+    common::with_mounted(common::filesystem(connection), |path| {
+        fs::write(path.join("log"), &text).unwrap();
+    });
+
+    let chat = MockChat::in_dir(&directory);
+    let downloaded = chat.download_counter();
+    let connection = TgConnection::with_chat(chat);
+    common::with_mounted(common::filesystem(connection), |path| {
+        let mut file = File::open(path.join("log")).unwrap();
+        file.seek(SeekFrom::Start(3_500_000)).unwrap();
+        let mut data = vec![0u8; 1000];
+        file.read_exact(&mut data).unwrap();
+        assert_eq!(&text.as_bytes()[3_500_000..3_501_000], &data[..]);
+
+        // Only the frames around the range are downloaded, not the whole chunk
+        let compressed_size: u64 = fs::read_dir(directory.path().join("uploads"))
+            .unwrap()
+            .map(|x| x.unwrap().metadata().unwrap().len())
+            .sum();
+        assert!((downloaded.load(Ordering::SeqCst) as u64) < compressed_size / 2);
+
+        assert_eq!(text, fs::read_to_string(path.join("log")).unwrap());
+    });
+}
+
+#[test]
+fn copies_share_the_content() {
+    let directory = tempfile::tempdir().unwrap();
+    let content = common::content(4000);
+    let uploads = || {
+        fs::read_dir(directory.path().join("uploads"))
+            .unwrap()
+            .count()
+    };
+
+    let connection = TgConnection::with_chat(MockChat::in_dir(&directory)).with_chunk_size(1000);
+    common::with_mounted(common::filesystem(connection), |path| {
+        fs::write(path.join("file"), &content).unwrap();
+        assert_eq!(4, uploads());
+
+        fs::copy(path.join("file"), path.join("copy")).unwrap();
+        // Saving the same content again doesn't upload it either
+        fs::write(path.join("file"), &content).unwrap();
+        assert_eq!(4, uploads());
+
+        fs::remove_file(path.join("file")).unwrap();
+        assert_eq!(content, fs::read(path.join("copy")).unwrap());
+    });
+}
+
+#[test]
+fn symlinks() {
+    let directory = tempfile::tempdir().unwrap();
+
+    let filesystem = common::mock_filesystem(&directory);
+    common::with_mounted(filesystem, |path| {
+        fs::create_dir(path.join("dir")).unwrap();
+        fs::write(path.join("dir").join("file"), "hello").unwrap();
+        std::os::unix::fs::symlink("dir/file", path.join("link")).unwrap();
+
+        assert_eq!("hello", fs::read_to_string(path.join("link")).unwrap());
+        assert!(fs::symlink_metadata(path.join("link"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+    });
+
+    // The target is stored, not only cached
+    let filesystem = common::mock_filesystem(&directory);
+    common::with_mounted(filesystem, |path| {
+        let target = fs::read_link(path.join("link")).unwrap();
+        assert_eq!(Path::new("dir/file"), target);
+
+        let mut entries: Vec<(String, bool, bool)> = fs::read_dir(path)
+            .unwrap()
+            .map(|x| x.unwrap())
+            .map(|x| {
+                let file_type = x.file_type().unwrap();
+                let name = x.file_name().into_string().unwrap();
+                (name, file_type.is_dir(), file_type.is_symlink())
+            })
+            .collect();
+        entries.sort();
+        let expected = vec![
+            ("dir".to_string(), true, false),
+            ("link".to_string(), false, true),
+        ];
+        assert_eq!(expected, entries);
+
+        fs::remove_file(path.join("link")).unwrap();
+        assert_eq!(
+            "hello",
+            fs::read_to_string(path.join("dir").join("file")).unwrap()
+        );
+    });
+}
+
+#[test]
+fn hard_links() {
+    use std::os::unix::fs::MetadataExt;
+
+    let directory = tempfile::tempdir().unwrap();
+
+    let filesystem = common::mock_filesystem(&directory);
+    common::with_mounted(filesystem, |path| {
+        fs::create_dir(path.join("dir")).unwrap();
+        fs::write(path.join("file"), "hello").unwrap();
+        fs::hard_link(path.join("file"), path.join("dir").join("link")).unwrap();
+        fs::hard_link(path.join("file"), path.join("same_dir")).unwrap();
+
+        assert_eq!(3, fs::metadata(path.join("file")).unwrap().nlink());
+        let ino = fs::metadata(path.join("file")).unwrap().ino();
+        assert_eq!(
+            ino,
+            fs::metadata(path.join("dir").join("link")).unwrap().ino()
+        );
+
+        // The content is shared
+        fs::write(path.join("dir").join("link"), "world").unwrap();
+        assert_eq!("world", fs::read_to_string(path.join("same_dir")).unwrap());
+
+        fs::remove_file(path.join("file")).unwrap();
+        assert_eq!(2, fs::metadata(path.join("same_dir")).unwrap().nlink());
+    });
+
+    let filesystem = common::mock_filesystem(&directory);
+    common::with_mounted(filesystem, |path| {
+        let link = path.join("dir").join("link");
+        assert_eq!("world", fs::read_to_string(&link).unwrap());
+        assert!(!path.join("file").exists());
+
+        fs::rename(path.join("same_dir"), path.join("dir").join("renamed")).unwrap();
+        fs::remove_file(&link).unwrap();
+        let renamed = path.join("dir").join("renamed");
+        assert_eq!(1, fs::metadata(&renamed).unwrap().nlink());
+        assert_eq!("world", fs::read_to_string(&renamed).unwrap());
+
+        fs::remove_file(&renamed).unwrap();
+        assert_eq!(0, fs::read_dir(path.join("dir")).unwrap().count());
+    });
+}
+
+#[test]
+fn non_empty_directory_is_not_removed() {
+    let filesystem = common::filesystem(TgConnection::with_chat(MockChat::in_memory()));
+
+    common::with_mounted(filesystem, |path| {
+        fs::create_dir(path.join("dir")).unwrap();
+        fs::write(path.join("dir").join("file"), "hello").unwrap();
+        fs::create_dir(path.join("other")).unwrap();
+        fs::write(path.join("other").join("file"), "world").unwrap();
+
+        let error = fs::remove_dir(path.join("dir")).unwrap_err();
+        assert_eq!(Some(libc::ENOTEMPTY), error.raw_os_error());
+        let error = fs::rename(path.join("other"), path.join("dir")).unwrap_err();
+        assert_eq!(Some(libc::ENOTEMPTY), error.raw_os_error());
+        assert_eq!(
+            "hello",
+            fs::read_to_string(path.join("dir").join("file")).unwrap()
+        );
+
+        fs::remove_file(path.join("dir").join("file")).unwrap();
+        fs::rename(path.join("other"), path.join("dir")).unwrap();
+        assert_eq!(
+            "world",
+            fs::read_to_string(path.join("dir").join("file")).unwrap()
+        );
+        assert!(!path.join("other").exists());
+    });
+}
+
+#[test]
+fn rename_over_existing_file() {
+    use std::os::unix::fs::MetadataExt;
+
+    let directory = tempfile::tempdir().unwrap();
+
+    let filesystem = common::mock_filesystem(&directory);
+    common::with_mounted(filesystem, |path| {
+        fs::write(path.join("new"), "new content").unwrap();
+        fs::write(path.join("old"), "old content").unwrap();
+        fs::write(path.join("linked"), "linked content").unwrap();
+        fs::hard_link(path.join("linked"), path.join("link")).unwrap();
+
+        fs::rename(path.join("new"), path.join("old")).unwrap();
+        fs::rename(path.join("old"), path.join("linked")).unwrap();
+
+        assert_eq!(
+            "new content",
+            fs::read_to_string(path.join("linked")).unwrap()
+        );
+        assert_eq!(1, fs::metadata(path.join("link")).unwrap().nlink());
+    });
+
+    // The content of the replaced file is removed
+    let messages = fs::read_to_string(directory.path().join("messages.json")).unwrap();
+    assert_eq!(2, messages.matches("[CHUNK]").count());
+
+    let filesystem = common::mock_filesystem(&directory);
+    common::with_mounted(filesystem, |path| {
+        let mut names: Vec<String> = fs::read_dir(path)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(vec!["link", "linked"], names);
+
+        assert_eq!(
+            "new content",
+            fs::read_to_string(path.join("linked")).unwrap()
+        );
+        assert_eq!(1, fs::metadata(path.join("linked")).unwrap().nlink());
+        assert_eq!(
+            "linked content",
+            fs::read_to_string(path.join("link")).unwrap()
+        );
+        assert_eq!(1, fs::metadata(path.join("link")).unwrap().nlink());
+    });
+}
+
+#[test]
+fn rename_over_other_type() {
+    let directory = tempfile::tempdir().unwrap();
+
+    let filesystem = common::mock_filesystem(&directory);
+    common::with_mounted(filesystem, |path| {
+        fs::write(path.join("file"), "content").unwrap();
+        fs::create_dir(path.join("dir")).unwrap();
+
+        let error = fs::rename(path.join("dir"), path.join("file")).unwrap_err();
+        assert_eq!(Some(libc::ENOTDIR), error.raw_os_error());
+        let error = fs::rename(path.join("file"), path.join("dir")).unwrap_err();
+        assert_eq!(Some(libc::EISDIR), error.raw_os_error());
+
+        assert_eq!("content", fs::read_to_string(path.join("file")).unwrap());
+        assert!(fs::metadata(path.join("dir")).unwrap().is_dir());
+    });
+}
+
+#[test]
+fn other_format_is_not_mounted() {
+    let runtime = Runtime::new().unwrap();
+    let chat = MockChat::in_memory();
+    let meta = "[META]\n{\"version\":\"v1\",\"files\":{},\"next_ino\":1}".to_string();
+    runtime.block_on(chat.send_message(meta, None)).unwrap();
+
+    let connection = TgConnection::with_chat(chat);
+    let result = runtime.block_on(connection.check_version());
+    assert!(matches!(result, Err(FsError::Unsupported(version)) if version == "v1"));
+}
+
+#[test]
+fn two_mounts_of_one_chat() {
+    use std::os::unix::fs::MetadataExt;
+
+    let chat = Arc::new(MockChat::in_memory());
+
+    let first = common::filesystem(TgConnection::with_chat(chat.clone()));
+    let second = common::filesystem(TgConnection::with_chat(chat.clone()));
+    common::with_mounted(first, |first_path| {
+        fs::create_dir(first_path.join("first")).unwrap();
+        common::with_mounted(second, |second_path| {
+            fs::create_dir(second_path.join("second")).unwrap();
+
+            // Both mounts allocate inodes at the same time
+            let dirs = vec![first_path.join("first"), second_path.join("second")];
+            let writers: Vec<_> = dirs
+                .into_iter()
+                .map(|dir| {
+                    thread::spawn(move || {
+                        for i in 0..150 {
+                            fs::write(dir.join(format!("file_{}", i)), "hello").unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().unwrap();
+            }
+        });
+    });
+
+    let filesystem = common::filesystem(TgConnection::with_chat(chat));
+    common::with_mounted(filesystem, |path| {
+        let mut inodes = vec![];
+        for dir in &["first", "second"] {
+            for entry in fs::read_dir(path.join(dir)).unwrap() {
+                inodes.push(entry.unwrap().metadata().unwrap().ino());
+            }
+        }
+        assert_eq!(300, inodes.len());
+
+        inodes.sort();
+        inodes.dedup();
+        assert_eq!(300, inodes.len());
+    });
+}
+
+#[test]
+fn shared_chunks_outlive_the_edit_window() {
+    let directory = tempfile::tempdir().unwrap();
+    let content = common::content(4000);
+    let chunk_messages = || {
+        fs::read_to_string(directory.path().join("messages.json"))
+            .unwrap()
+            .matches("[CHUNK]")
+            .count()
+    };
+
+    // No message can be edited after it's sent, the counts are kept in recreated shards
+    let chat = MockChat::in_dir(&directory).with_edit_window(Duration::from_secs(0));
+    let connection = TgConnection::with_chat(chat).with_chunk_size(1000);
+    common::with_mounted(common::filesystem(connection), |path| {
+        fs::write(path.join("file"), &content).unwrap();
+        fs::copy(path.join("file"), path.join("copy")).unwrap();
+        assert_eq!(4, chunk_messages());
+
+        fs::remove_file(path.join("file")).unwrap();
+        assert_eq!(content, fs::read(path.join("copy")).unwrap());
+        assert_eq!(4, chunk_messages());
+
+        // The last file frees the chunks
+        fs::remove_file(path.join("copy")).unwrap();
+        assert_eq!(0, chunk_messages());
+    });
+}
+
+#[test]
+fn file_with_many_chunks() {
+    let directory = tempfile::tempdir().unwrap();
+    let content = common::content(10_000);
+    let chunk_pages = || {
+        fs::read_to_string(directory.path().join("messages.json"))
+            .unwrap()
+            .matches("[CHUNKS]")
+            .count()
+    };
+
+    // 100 chunks don't fit into the link
+    let connection = TgConnection::with_chat(MockChat::in_dir(&directory)).with_chunk_size(100);
+    common::with_mounted(common::filesystem(connection), |path| {
+        fs::write(path.join("file"), &content).unwrap();
+        assert!(chunk_pages() > 1);
+    });
+
+    let connection = TgConnection::with_chat(MockChat::in_dir(&directory)).with_chunk_size(100);
+    common::with_mounted(common::filesystem(connection), |path| {
+        assert_eq!(content, fs::read(path.join("file")).unwrap());
+
+        let mut file = File::open(path.join("file")).unwrap();
+        let mut buffer = vec![0u8; 1000];
+        file.seek(SeekFrom::Start(5050)).unwrap();
+        file.read_exact(&mut buffer).unwrap();
+        assert_eq!(&content[5050..6050], buffer.as_slice());
+
+        // The pages of the previous content are deleted
+        fs::write(path.join("file"), "short").unwrap();
+        assert_eq!(0, chunk_pages());
+        assert_eq!("short", fs::read_to_string(path.join("file")).unwrap());
+    });
+}